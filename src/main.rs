@@ -1,587 +1,4264 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::BufReader;
-use std::sync::Arc;
+use std::num::NonZeroU32;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 use governor::{DefaultDirectRateLimiter, Jitter, Quota, RateLimiter};
 use nonzero_ext::nonzero;
-use std::time::Duration;
-use reqwest::IntoUrl;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use reqwest::{IntoUrl, StatusCode};
 use serde::{Deserialize, Serialize, Serializer};
 use serde::ser::SerializeStruct;
+use serde::de::{DeserializeOwned, MapAccess, Visitor};
 use futures::{
     stream::futures_unordered::FuturesUnordered,
     StreamExt
 };
 use strum::IntoEnumIterator; // 0.17.1
 use strum_macros::EnumIter; // 0.17.1
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use async_trait::async_trait;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use tracing::{debug, info, trace, warn, Level};
 
-struct RateLimitedReqwestClient {
-    reqwest_client: reqwest::Client,
-    limiter: DefaultDirectRateLimiter,
-    jitter: Jitter
+#[derive(Parser, Debug)]
+#[command(name = "gw2_ap_yaml_generator", version, about, after_help = EXIT_CODES_HELP)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Input config file, or - for stdin
+    #[arg(short, long, default_value = "input.yaml")]
+    input: PathBuf,
+    /// Input format, guessed from the input file extension when omitted
+    #[arg(long, value_enum)]
+    input_format: Option<Format>,
+    /// Generated Archipelago yaml, or - for stdout
+    #[arg(short, long, default_value = "gw2.yaml")]
+    output: PathBuf,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Yaml)]
+    output_format: Format,
+    /// GW2 API key, overrides both input.yaml and GW2_API_KEY
+    #[arg(long)]
+    api_key: Option<String>,
+    /// Where to send API requests, for a proxy or a local mock server
+    #[arg(long, env = "GW2_API_BASE_URL", default_value = "https://api.guildwars2.com")]
+    api_base_url: String,
+    /// How many times to retry a failed API request
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// Base delay before the first retry, doubled on each further attempt
+    #[arg(long, default_value_t = 500)]
+    retry_delay_ms: u64,
+    /// Language for season and quest names
+    #[arg(long, value_enum, default_value_t = Lang::En)]
+    lang: Lang,
+    /// Maximum API requests per minute
+    #[arg(long, default_value_t = 300)]
+    rate_limit: u32,
+    /// Longest random delay added before each API request, so parallel requests don't fire in lockstep
+    #[arg(long, default_value_t = 1000)]
+    jitter_ms: u64,
+    /// Give up on the whole run after this many seconds, writing nothing
+    #[arg(long, value_name = "SECS")]
+    timeout_total: Option<u64>,
+    /// Seconds to wait for an API response before retrying
+    #[arg(long, default_value_t = 30)]
+    timeout_secs: u64,
+    /// Maximum number of API requests in flight at once
+    #[arg(long, default_value_t = 16)]
+    max_concurrency: usize,
+    /// Share the request budget with earlier runs through this file, best effort
+    #[arg(long, value_name = "FILE")]
+    rate_state: Option<PathBuf>,
+    /// Record the API data used into this file, or replay it when the file already exists
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<PathBuf>,
+    /// Read API data from JSON fixtures in this directory instead of the network
+    #[arg(long, value_name = "DIR")]
+    offline: Option<PathBuf>,
+    /// Directory for cached story and quest data, and completed quests to revalidate
+    #[arg(long, default_value = "cache")]
+    cache_dir: PathBuf,
+    /// How long cached story and quest data stays fresh
+    #[arg(long, default_value_t = 168)]
+    cache_ttl_hours: u64,
+    /// Ignore cached story and quest data and fetch it again
+    #[arg(long)]
+    no_cache: bool,
+    /// What happens to storylines left out of a character's storyline map
+    #[arg(long, value_enum, default_value_t = StorylineMode::Explicit)]
+    storyline_mode: StorylineMode,
+    /// The order character triggers are written in
+    #[arg(long, value_enum, default_value_t = CharacterOrder::Alpha)]
+    sort_characters: CharacterOrder,
+    /// Raise achievement_weight's fixed value with the account's daily and monthly AP, the only points /v2/account
+    /// reports, up to double at their combined cap of 30000. Other achievement points aren't counted
+    #[arg(long)]
+    scale_by_daily_monthly_ap: bool,
+    /// Weigh storylines the input gives no weight by how much of them the character has completed
+    #[arg(long, value_enum, default_value_t = AutoWeight::Off)]
+    auto_weight: AutoWeight,
+    /// Leave out storylines with fewer quests than this left for the character
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    min_remaining: usize,
+    /// Generate for every character on the account, using default settings for any the input doesn't list
+    #[arg(long)]
+    all_characters: bool,
+    /// Only generate for this character, can be repeated
+    #[arg(long, value_name = "NAME")]
+    only: Vec<String>,
+    /// Whether a character whose data can't be fetched stops the run or is left out.
+    /// Characters without quest data are generated either way, as if nothing were completed
+    #[arg(long, value_enum, default_value_t = OnError::Fail)]
+    on_error: OnError,
+    /// Fail instead of warning on input problems or configured characters missing from the account
+    #[arg(long)]
+    strict: bool,
+    /// Write per-character storyline quest completion to this CSV file
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+    /// Only fetch the quests characters have completed, using the hardcoded storyline quest counts
+    #[arg(long, conflicts_with_all = ["report", "include_future_storylines"])]
+    limit_quests_detail: bool,
+    /// Also roll seasons the API has that this version doesn't know, at a default weight and their counted quests.
+    /// Their storyline values are new to Archipelago too, so the output only loads with an apworld that has them
+    #[arg(long, conflicts_with = "validate_output")]
+    include_future_storylines: bool,
+    /// Check the generated options against the bundled GW2 option schema
+    #[arg(long)]
+    validate_output: bool,
+    /// Leave zero weights out of the generated yaml
+    #[arg(long)]
+    prune_zeros: bool,
+    /// Only replace the characters, triggers and storylines in an existing output file, backing it up first
+    #[arg(long)]
+    merge: bool,
+    /// Read the written output back and check it matches what was generated, so it needs an output file
+    #[arg(long, conflicts_with_all = ["merge", "split_by_character", "dry_run"])]
+    verify_roundtrip: bool,
+    /// Copy an existing output file to <file>.<unix time>.bak before overwriting it
+    #[arg(long)]
+    backup: bool,
+    /// How many --backup copies to keep per output file, removing the oldest. The newest is always kept
+    #[arg(long, value_name = "N", default_value_t = 5, requires = "backup")]
+    keep_backups: usize,
+    /// Write the global options and each character's triggers as separate yaml fragments in this directory,
+    /// for reviewing per character. The fragments have to be assembled back into one file for Archipelago
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["merge", "dry_run"])]
+    split_by_character: Option<PathBuf>,
+    /// Lay out the yaml for reading instead of as serde_yaml writes it
+    #[arg(long, value_enum, value_name = "STYLE")]
+    pretty: Option<YamlStyle>,
+    /// Comment each storyline trigger with how many of its quests the character has completed
+    #[arg(long)]
+    annotate: bool,
+    /// Start the yaml with a comment saying what generated it, when, against which game build and from which input
+    #[arg(long)]
+    stamp: bool,
+    /// Print the generated yaml to stdout instead of writing the output file
+    #[arg(long)]
+    dry_run: bool,
+    /// Log whether each quest of the character's storylines is complete
+    #[arg(long)]
+    verbose_quests: bool,
+    /// Log more detail to stderr, repeat for even more
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Only log warnings and errors, without progress bars or the summary
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
 }
 
-impl RateLimitedReqwestClient {
-    fn new() -> Self {
-        Self {
-            reqwest_client: reqwest::Client::new(),
-            limiter: RateLimiter::direct(Quota::per_minute(nonzero!(300u32))),
-            jitter: Jitter::up_to(Duration::from_secs(1)),
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the storyline and storyline group names accepted in the input, without calling the API
+    Storylines,
+    /// Check that the API still has the season behind every storyline
+    Validate,
+    /// Check that the API is reachable and the API key works, without generating anything
+    Check,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Yaml,
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum StorylineMode {
+    /// Only the listed storylines are rolled
+    Explicit,
+    /// Unlisted storylines are rolled at their default weight
+    Default,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OnError {
+    /// Stop on the first character that fails
+    Fail,
+    /// Leave failed characters out and carry on
+    Skip,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum YamlStyle {
+    /// Write small weight tables on one line, where they fit in 100 columns
+    Compact,
+    /// Leave a blank line between options and between triggers
+    Expanded,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CharacterOrder {
+    /// By name
+    Alpha,
+    /// As listed in the input, then any others by name
+    Input,
+}
+
+// Weights for storylines without one in the input, from the share of quests completed:
+// favor-incomplete rolls 100 * remaining / total, favor-complete 100 * completed / total, never below 1
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum AutoWeight {
+    /// Use each storyline's default weight
+    Off,
+    /// Favor the storylines with the most quests left
+    FavorIncomplete,
+    /// Favor the storylines closest to finished
+    FavorComplete,
+}
+
+impl AutoWeight {
+    fn weight(&self, default_weight: u32, remaining: usize, total: usize) -> u32 {
+        let share = match self {
+            AutoWeight::Off => return default_weight,
+            AutoWeight::FavorIncomplete => remaining,
+            AutoWeight::FavorComplete => total.saturating_sub(remaining),
+        };
+        (share * 100).div_ceil(total.max(1)).max(1) as u32
+    }
+}
+
+// Languages the GW2 API localizes names into
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Lang {
+    En,
+    Es,
+    De,
+    Fr,
+    Zh,
+}
+
+impl Lang {
+    const fn code(&self) -> &str {
+        match self {
+            Lang::En => "en",
+            Lang::Es => "es",
+            Lang::De => "de",
+            Lang::Fr => "fr",
+            Lang::Zh => "zh",
         }
     }
+}
 
-    async fn get<U>(&self, uri: U) -> reqwest::RequestBuilder where U: IntoUrl {
-        self.limiter.until_ready_with_jitter(self.jitter).await;
-        self.reqwest_client.get(uri)
+impl Format {
+    fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "yaml" | "yml" => Some(Format::Yaml),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug)]
-enum OptionValue{
-    Value(String),
-    Table(HashMap<String, u32>),
+// Replaces the value of any access_token query parameter so keys never reach the logs
+fn redact_url(uri: &str) -> String {
+    let mut redacted = String::with_capacity(uri.len());
+    let mut rest = uri;
+    while let Some(start) = rest.find("access_token=") {
+        let value_start = start + "access_token=".len();
+        redacted.push_str(&rest[..value_start]);
+        redacted.push_str("***");
+        rest = &rest[value_start..];
+        rest = &rest[rest.find('&').unwrap_or(rest.len())..];
+    }
+    redacted.push_str(rest);
+    redacted
 }
 
-impl OptionValue {
-    fn insert(&mut self, value: String, weight: u32) -> Option<u32> {
+// Expands a leading `~` to the home directory and makes the path absolute
+fn resolve_path(path: &Path) -> PathBuf {
+    let expanded = match path.strip_prefix("~") {
+        Ok(rest) => {
+            let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+            match home {
+                Some(home) => PathBuf::from(home).join(rest),
+                None => path.to_path_buf(),
+            }
+        }
+        Err(_) => path.to_path_buf(),
+    };
+    std::path::absolute(&expanded).unwrap_or(expanded)
+}
+
+enum GenError {
+    MissingApiKey(PathBuf),
+    InvalidApiKey(String),
+    MissingPermissions(Vec<&'static str>),
+    CharacterNotFound(String),
+    InvalidInput(Vec<String>),
+    TriggerCollisions(Vec<String>),
+    InvalidOutput(Vec<String>),
+    RoundTrip(PathBuf, Vec<String>),
+    MissingSeasons(Vec<String>),
+    Config(String),
+    // Reading the input, fixtures or a snapshot, as opposed to File for writing output
+    Read(PathBuf, std::io::Error),
+    File(PathBuf, std::io::Error),
+    InputParse(PathBuf, Box<dyn std::error::Error + Send + Sync>),
+    Yaml(serde_yaml::Error),
+    Json(serde_json::Error),
+    Fetch(FetchError),
+    Task(tokio::task::JoinError),
+    OptionValue(OptionValueError),
+    Interrupted,
+    TimedOut(u64, &'static str),
+}
+
+impl fmt::Display for GenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            OptionValue::Table(map) => {map.insert(value, weight)},
-            _ => panic!("Can only insert into a table")
+            GenError::MissingApiKey(input_path) => write!(f,
+                "no API key found; set api_key in {}, the GW2_API_KEY environment variable, or pass --api-key",
+                input_path.display()),
+            GenError::InvalidApiKey(message) => write!(f,
+                "the API rejected the key ({}); check it was copied completely and hasn't been deleted", message),
+            GenError::MissingPermissions(permissions) => write!(f,
+                "API key is missing the required permission(s): {}", permissions.join(", ")),
+            GenError::CharacterNotFound(name) => write!(f,
+                "character {} is not on the account; mark it with `new: true` if it hasn't been created yet", name),
+            GenError::InvalidInput(problems) => write!(f, "invalid input: {}", problems.join("; ")),
+            GenError::TriggerCollisions(results) => write!(f,
+                "generated triggers share the same option_result: {}", results.join(", ")),
+            GenError::InvalidOutput(problems) => write!(f,
+                "generated options don't match the GW2 option schema: {}", problems.join("; ")),
+            GenError::RoundTrip(path, options) => write!(f,
+                "{} doesn't read back as what was generated, these differ: {}", path.display(), options.join(", ")),
+            GenError::MissingSeasons(seasons) => write!(f,
+                "storyline seasons missing from the API: {}", seasons.join(", ")),
+            GenError::Config(message) => write!(f, "{}", message),
+            GenError::Read(path, err) | GenError::File(path, err) => write!(f, "{}: {}", path.display(), err),
+            GenError::InputParse(path, err) => write!(f, "failed to parse {}: {}", path.display(), err),
+            GenError::Yaml(err) => write!(f, "failed to serialize output: {}", err),
+            GenError::Json(err) => write!(f, "failed to serialize output: {}", err),
+            GenError::Fetch(err) => write!(f, "request failed: {}", err),
+            GenError::Task(err) => write!(f, "request task failed: {}", err),
+            GenError::OptionValue(err) => write!(f, "failed to build a trigger: {}", err),
+            GenError::Interrupted => write!(f, "interrupted, nothing was written"),
+            GenError::TimedOut(secs, stage) => write!(f,
+                "gave up after {} seconds while {}, nothing was written", secs, stage),
         }
     }
 }
 
-impl Serialize for OptionValue {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+// Debug is kept to the one-line message as well, for errors printed with {:?}
+impl fmt::Debug for GenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for GenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            OptionValue::Value(val) => {val.serialize(serializer)}
-            OptionValue::Table(table) => {table.serialize(serializer)}
+            GenError::MissingApiKey(_) | GenError::InvalidApiKey(_) | GenError::MissingPermissions(_)
+            | GenError::CharacterNotFound(_) | GenError::InvalidInput(_) | GenError::TriggerCollisions(_) | GenError::InvalidOutput(_)
+            | GenError::RoundTrip(..) | GenError::MissingSeasons(_) | GenError::Config(_) | GenError::Interrupted | GenError::TimedOut(..) => None,
+            GenError::Read(_, err) | GenError::File(_, err) => Some(err),
+            GenError::InputParse(_, err) => Some(err.as_ref()),
+            GenError::Yaml(err) => Some(err),
+            GenError::Json(err) => Some(err),
+            GenError::Fetch(err) => Some(err),
+            GenError::Task(err) => Some(err),
+            GenError::OptionValue(err) => Some(err),
         }
     }
 }
 
-#[derive(Serialize, Debug)]
-struct Trigger {
-    option_category: String, // Always "Guild Wars 2"
-    option_name: String,
-    option_result: String,
-    options: HashMap<String, HashMap<String, OptionValue>>,
-}
+impl GenError {
+    // A 404 from the API or a missing fixture file
+    fn is_not_found(&self) -> bool {
+        match self {
+            GenError::Fetch(FetchError::Status { status, .. }) => *status == StatusCode::NOT_FOUND,
+            GenError::Read(_, err) => err.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
 
-impl Trigger {
-    fn new(option_name: String, option_result: String) -> Self {
-        Trigger {
-            option_category: "Guild Wars 2".to_string(),
-            option_name,
-            option_result,
-            options: HashMap::new(),
+    // Kept in step with EXIT_CODES_HELP
+    fn exit_code(&self) -> u8 {
+        match self {
+            GenError::MissingApiKey(_) | GenError::CharacterNotFound(_) | GenError::InvalidInput(_)
+            | GenError::Config(_) | GenError::Read(..) | GenError::InputParse(..) => 2,
+            GenError::InvalidApiKey(_) | GenError::MissingPermissions(_) => 3,
+            GenError::Fetch(_) | GenError::Task(_) | GenError::MissingSeasons(_) => 4,
+            GenError::File(..) | GenError::Yaml(_) | GenError::Json(_) => 5,
+            GenError::TriggerCollisions(_) | GenError::InvalidOutput(_) | GenError::RoundTrip(..) => 6,
+            GenError::OptionValue(_) => 1,
+            GenError::TimedOut(..) => 124,
+            GenError::Interrupted => 130,
         }
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct Input {
-    api_key: String,
-    characters: HashMap<String, CharacterInput>,
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0    success
+  1    internal error
+  2    bad input, config or API key setup
+  3    the API rejected the key or it lacks permissions
+  4    network or API failure
+  5    the output could not be written
+  6    the generated output failed a check
+  124  --timeout-total ran out
+  130  interrupted";
+
+impl From<serde_yaml::Error> for GenError {
+    fn from(err: serde_yaml::Error) -> Self {
+        GenError::Yaml(err)
+    }
 }
 
+impl From<serde_json::Error> for GenError {
+    fn from(err: serde_json::Error) -> Self {
+        GenError::Json(err)
+    }
+}
 
-const fn default_weight() -> u32 {50}
-#[derive(Deserialize, Debug)]
-struct CharacterInput {
-    #[serde(default = "default_weight")]
-    weight: u32,
-    storyline: Option<HashMap<String, u32>>,
+impl From<OptionValueError> for GenError {
+    fn from(err: OptionValueError) -> Self {
+        GenError::OptionValue(err)
+    }
 }
 
-#[derive(Serialize, Debug)]
-struct Output {
-    name: String,
-    description: String,
-    game: String,
-    #[serde(rename = "Guild Wars 2")]
-    game_options: OutputOptions
+impl From<FetchError> for GenError {
+    fn from(err: FetchError) -> Self {
+        GenError::Fetch(err)
+    }
+}
 
+enum FetchError {
+    Http(reqwest::Error),
+    // The connection failed partway through a successful response
+    Body(reqwest::Error),
+    Status { status: StatusCode, message: String },
+    // The response wasn't the shape we expected, uri is already redacted
+    Parse { uri: String, type_name: String, err: serde_json::Error },
 }
 
-impl Output {
-    fn new() -> Self {
-        Self {
-            name: "Player{number}".to_string(),
-            description: "Customized Guild Wars 2 Template".to_string(),
-            game: "Guild Wars 2".to_string(),
-            game_options: OutputOptions::new(),
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Http(err) => write!(f, "{}", redact_url(&err.to_string())),
+            FetchError::Body(err) => write!(f, "failed to read the response: {}", redact_url(&err.to_string())),
+            FetchError::Status { status, message } => write!(f, "{}: {}", status, message),
+            FetchError::Parse { uri, type_name, err } => write!(f, "failed to parse {} from {}: {}", type_name, uri, err),
         }
     }
 }
 
-impl Default for Output {
-    fn default() -> Self {
-        let mut val = Self::new();
-        val.game_options = OutputOptions::default();
-
-        val
+impl fmt::Debug for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
     }
 }
 
-#[derive(Serialize, Debug)]
-struct OutputOptions {
-    progression_balancing: HashMap<String, u32>,
-    accessibility: HashMap<String, u32>,
-    character: HashMap<String, u32>,
-    triggers: Vec<Trigger>,
-    character_profession: HashMap<String, u32>,
-    character_race: HashMap<String, u32>,
-    starting_mainhand_weapon: HashMap<String, u32>,
-    starting_offhand_weapon: HashMap<String, u32>,
-    group_content: HashMap<String, u32>,
-    include_competitive: HashMap<String, u32>,
-    achievement_weight: HashMap<String, u32>,
-    quest_weight: HashMap<String, u32>,
-    training_weight: HashMap<String, u32>,
-    world_boss_weight: HashMap<String, u32>,
-    unique_item_weight: HashMap<String, u32>,
-    poi_weight: HashMap<String, u32>,
-    storyline: HashMap<String, u32>,
-    mist_fragments_required: u32,
-    extra_mist_fragment_percent: u32,
-    heal_skill: HashMap<String, u32>,
-    gear_slots: HashMap<String, u32>,
-    storyline_items: HashMap<String, u32>,
+impl std::error::Error for FetchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FetchError::Http(err) | FetchError::Body(err) => Some(err),
+            FetchError::Status { .. } => None,
+            FetchError::Parse { err, .. } => Some(err),
+        }
+    }
 }
 
-impl OutputOptions {
-    fn new() -> Self {
-        OutputOptions {
-            progression_balancing: HashMap::new(),
-            accessibility: HashMap::new(),
-            character: HashMap::new(),
-            triggers: Vec::new(),
-            character_profession: HashMap::new(),
-            character_race: HashMap::new(),
-            starting_mainhand_weapon: HashMap::new(),
-            starting_offhand_weapon: HashMap::new(),
-            group_content: HashMap::new(),
-            include_competitive: HashMap::new(),
-            achievement_weight: HashMap::new(),
-            quest_weight: HashMap::new(),
-            training_weight: HashMap::new(),
-            world_boss_weight: HashMap::new(),
-            unique_item_weight: HashMap::new(),
-            poi_weight: HashMap::new(),
-            storyline: HashMap::new(),
-            mist_fragments_required: 10,
-            extra_mist_fragment_percent: 50,
-            heal_skill: HashMap::new(),
-            gear_slots: HashMap::new(),
-            storyline_items: HashMap::new(),
+impl FetchError {
+    // Network failures, timeouts, server errors and throttling are worth retrying, other client errors are not
+    fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Http(err) => err.is_timeout() || (!err.is_builder() && !err.is_decode() && !err.is_status()),
+            FetchError::Body(_) => true,
+            FetchError::Status { status, .. } =>
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+            FetchError::Parse { .. } => false,
         }
     }
 }
 
-impl Default for OutputOptions {
-    fn default() -> Self {
-        let mut val = Self::new();
+// type_name without module paths, e.g. Vec<Quest> rather than alloc::vec::Vec<gw2YamlGenerator::Quest>
+fn short_type_name<T>() -> String {
+    std::any::type_name::<T>()
+        .split_inclusive(['<', '>', ',', ' '])
+        .map(|part| part.rsplit_once("::").map_or(part, |(_, name)| name))
+        .collect()
+}
 
-        val.progression_balancing.insert("random".to_string(), 0);
-        val.progression_balancing.insert("random-low".to_string(), 0);
-        val.progression_balancing.insert("random-high".to_string(), 0);
-        val.progression_balancing.insert("disabled".to_string(), 0);
-        val.progression_balancing.insert("normal".to_string(), 50);
-        val.progression_balancing.insert("extreme".to_string(), 0);
+// The API explains errors in a {"text": ...} body, fall back to whatever else it sent
+async fn api_error_message(response: reqwest::Response) -> String {
+    #[derive(Deserialize)]
+    struct ApiErrorBody {
+        text: String,
+    }
 
-        val.accessibility.insert("locations".to_string(), 0);
-        val.accessibility.insert("items".to_string(), 50);
-        val.accessibility.insert("minimal".to_string(), 0);
+    let body = response.text().await.unwrap_or_default();
+    match serde_json::from_str::<ApiErrorBody>(&body) {
+        Ok(error) => error.text,
+        Err(_) if body.trim().is_empty() => "no error message".to_string(),
+        Err(_) => body.trim().to_string(),
+    }
+}
 
-        val.starting_mainhand_weapon.insert("none".to_string(), 0);
-        val.starting_mainhand_weapon.insert("axe".to_string(), 0);
-        val.starting_mainhand_weapon.insert("dagger".to_string(), 0);
-        val.starting_mainhand_weapon.insert("mace".to_string(), 0);
-        val.starting_mainhand_weapon.insert("pistol".to_string(), 0);
-        val.starting_mainhand_weapon.insert("sword".to_string(), 0);
-        val.starting_mainhand_weapon.insert("scepter".to_string(), 0);
-        val.starting_mainhand_weapon.insert("greatsword".to_string(), 0);
-        val.starting_mainhand_weapon.insert("hammer".to_string(), 0);
-        val.starting_mainhand_weapon.insert("longbow".to_string(), 0);
-        val.starting_mainhand_weapon.insert("rifle".to_string(), 0);
-        val.starting_mainhand_weapon.insert("short_bow".to_string(), 0);
-        val.starting_mainhand_weapon.insert("staff".to_string(), 0);
-        val.starting_mainhand_weapon.insert("random_proficient".to_string(), 50);
-        val.starting_mainhand_weapon.insert("random_proficient_one_handed".to_string(), 0);
-        val.starting_mainhand_weapon.insert("random_proficient_two_handed".to_string(), 0);
+impl From<tokio::task::JoinError> for GenError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        GenError::Task(err)
+    }
+}
 
-        val.starting_offhand_weapon.insert("none".to_string(), 0);
-        val.starting_offhand_weapon.insert("scepter".to_string(), 0);
-        val.starting_offhand_weapon.insert("focus".to_string(), 0);
-        val.starting_offhand_weapon.insert("shield".to_string(), 0);
-        val.starting_offhand_weapon.insert("torch".to_string(), 0);
-        val.starting_offhand_weapon.insert("warhorn".to_string(), 0);
-        val.starting_offhand_weapon.insert("random_proficient".to_string(), 50);
+// Shared behind an Arc by every spawned request, so they all draw on the one connection pool
+struct RateLimitedReqwestClient {
+    reqwest_client: reqwest::Client,
+    limiter: DefaultDirectRateLimiter,
+    jitter: Jitter,
+    max_retries: u32,
+    retry_base_delay: Duration,
+    request_timeout: Duration,
+    in_flight: Semaphore,
+    // When each request in the last minute was sent, for --rate-state
+    sent: Mutex<Vec<SystemTime>>,
+}
 
-        val.group_content.insert("none".to_string(), 50);
-        val.group_content.insert("five_man".to_string(), 25);
-        val.group_content.insert("ten_man".to_string(), 10);
+// The API's rate limit is per minute
+const RATE_WINDOW: Duration = Duration::from_secs(60);
 
-        val.include_competitive.insert("false".to_string(), 50);
-        val.include_competitive.insert("true".to_string(), 10);
+// Bump deliberately after checking Season, Quest and Character still match the API's response shapes
+const SCHEMA_VERSION: &str = "2022-03-23T19:00:00.000Z";
 
-        val.achievement_weight.insert("500".to_string(), 50);
-        val.achievement_weight.insert("random".to_string(), 0);
-        val.achievement_weight.insert("random-low".to_string(), 0);
-        val.achievement_weight.insert("random-high".to_string(), 0);
+impl RateLimitedReqwestClient {
+    fn new() -> Self {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("X-Schema-Version", reqwest::header::HeaderValue::from_static(SCHEMA_VERSION));
+        let reqwest_client = reqwest::Client::builder()
+            .user_agent(concat!("gw2_ap_yaml_generator/", env!("CARGO_PKG_VERSION")))
+            .default_headers(headers)
+            .connect_timeout(Duration::from_secs(10))
+            // At -vv every read and write is logged with its connection id, which shows how often connections are reused
+            .connection_verbose(tracing::enabled!(Level::TRACE))
+            .build()
+            .expect("failed to initialize the HTTP client");
 
-        val.quest_weight.insert("100".to_string(), 50);
-        val.quest_weight.insert("random".to_string(), 0);
-        val.quest_weight.insert("random-low".to_string(), 0);
-        val.quest_weight.insert("random-high".to_string(), 0);
+        Self {
+            reqwest_client,
+            limiter: RateLimiter::direct(Quota::per_minute(nonzero!(300u32))),
+            jitter: Jitter::up_to(Duration::from_secs(1)),
+            max_retries: 3,
+            retry_base_delay: Duration::from_millis(500),
+            request_timeout: Duration::from_secs(30),
+            in_flight: Semaphore::new(16),
+            sent: Mutex::new(Vec::new()),
+        }
+    }
 
-        val.training_weight.insert("100".to_string(), 50);
-        val.training_weight.insert("random".to_string(), 0);
-        val.training_weight.insert("random-low".to_string(), 0);
-        val.training_weight.insert("random-high".to_string(), 0);
+    fn with_quota(requests_per_minute: u32, jitter: Duration) -> Result<Self, GenError> {
+        let requests_per_minute = NonZeroU32::new(requests_per_minute)
+            .ok_or_else(|| GenError::Config("rate limit must be at least 1 request per minute".to_string()))?;
 
-        val.world_boss_weight.insert("250".to_string(), 50);
-        val.world_boss_weight.insert("random".to_string(), 0);
-        val.world_boss_weight.insert("random-low".to_string(), 0);
-        val.world_boss_weight.insert("random-high".to_string(), 0);
+        if jitter > RATE_WINDOW {
+            return Err(GenError::Config(format!("jitter must be at most {} seconds", RATE_WINDOW.as_secs())));
+        }
 
-        val.unique_item_weight.insert("250".to_string(), 50);
-        val.unique_item_weight.insert("random".to_string(), 0);
-        val.unique_item_weight.insert("random-low".to_string(), 0);
-        val.unique_item_weight.insert("random-high".to_string(), 0);
+        let mut client = Self::new();
+        client.limiter = RateLimiter::direct(Quota::per_minute(requests_per_minute));
+        client.jitter = Jitter::up_to(jitter);
+        Ok(client)
+    }
 
-        val.poi_weight.insert("250".to_string(), 50);
-        val.poi_weight.insert("random".to_string(), 0);
-        val.poi_weight.insert("random-low".to_string(), 0);
-        val.poi_weight.insert("random-high".to_string(), 0);
+    async fn get<U>(&self, uri: U) -> reqwest::RequestBuilder where U: IntoUrl {
+        self.limiter.until_ready_with_jitter(self.jitter).await;
+        {
+            let now = SystemTime::now();
+            let mut sent = self.sent.lock().expect("sent log is never poisoned");
+            sent.retain(|time| now.duration_since(*time).is_ok_and(|age| age < RATE_WINDOW));
+            sent.push(now);
+        }
+        self.reqwest_client.get(uri).timeout(self.request_timeout)
+    }
 
-        val.heal_skill.insert("randomize".to_string(), 1);
-        val.heal_skill.insert("early".to_string(), 10);
-        val.heal_skill.insert("starting".to_string(), 50);
+    // Spends the budget that requests from a previous run still hold in the current window.
+    // Best effort: runs overlapping in time, or with a different --rate-limit, aren't accounted for
+    fn restore_rate_state(&self, path: &Path) {
+        let Ok(file) = File::open(path) else {
+            return;
+        };
+        let millis: Vec<u64> = match serde_json::from_reader(BufReader::new(file)) {
+            Ok(millis) => millis,
+            Err(err) => {
+                warn!("Ignoring rate limit state {}: {}", path.display(), err);
+                return;
+            }
+        };
 
-        val.gear_slots.insert("randomize".to_string(), 5);
-        val.gear_slots.insert("early".to_string(), 50);
-        val.gear_slots.insert("starting".to_string(), 10);
+        let now = SystemTime::now();
+        let mut sent = self.sent.lock().expect("sent log is never poisoned");
+        for time in millis.into_iter().map(|millis| UNIX_EPOCH + Duration::from_millis(millis)) {
+            if now.duration_since(time).is_ok_and(|age| age < RATE_WINDOW) {
+                // Once the budget is gone the rest of the old requests don't matter
+                if self.limiter.check().is_err() {
+                    break;
+                }
+                sent.push(time);
+            }
+        }
+        debug!("Restored {} recent requests from {}", sent.len(), path.display());
+    }
 
-        val.storyline_items.insert("all".to_string(), 0);
-        val.storyline_items.insert("core".to_string(), 0);
-        val.storyline_items.insert("storyline".to_string(), 0);
-        val.storyline_items.insert("storyline_plus".to_string(), 50);
+    fn save_rate_state(&self, path: &Path) {
+        let now = SystemTime::now();
+        let millis: Vec<u64> = self.sent.lock().expect("sent log is never poisoned").iter()
+            .filter(|time| now.duration_since(**time).is_ok_and(|age| age < RATE_WINDOW))
+            .filter_map(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_millis() as u64)
+            .collect();
+        store_cache(path, &millis);
+    }
 
-        val
+    async fn get_json<T>(&self, uri: &str) -> Result<T, FetchError> where T: DeserializeOwned {
+        match self.get_json_if_changed(uri, None).await? {
+            Fetched::Changed { value, .. } => Ok(value),
+            // Only sent back for an If-None-Match, which isn't set here
+            Fetched::NotModified => Err(FetchError::Status {
+                status: StatusCode::NOT_MODIFIED,
+                message: "unexpected 304 Not Modified".to_string(),
+            }),
+        }
+    }
+
+    // Sends If-None-Match when given an etag, so an unchanged resource comes back as NotModified without a body
+    async fn get_json_if_changed<T>(&self, uri: &str, etag: Option<&str>) -> Result<Fetched<T>, FetchError>
+        where T: DeserializeOwned {
+        let mut attempt = 0;
+        loop {
+            let permit = self.in_flight.acquire().await.expect("semaphore is never closed");
+            debug!("GET {}", redact_url(uri));
+            let mut request = self.get(uri).await;
+            if let Some(etag) = etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            let (err, retry_after) = match request.send().await {
+                Ok(response) if response.status() == StatusCode::NOT_MODIFIED => return Ok(Fetched::NotModified),
+                Ok(response) if response.status().is_success() => {
+                    let etag = response.headers().get(reqwest::header::ETAG)
+                        .and_then(|etag| etag.to_str().ok())
+                        .map(str::to_string);
+                    match response.bytes().await {
+                        Ok(body) => {
+                            let value = serde_json::from_slice::<T>(&body).map_err(|err| FetchError::Parse {
+                                uri: redact_url(uri),
+                                type_name: short_type_name::<T>(),
+                                err,
+                            })?;
+                            return Ok(Fetched::Changed { value, etag });
+                        }
+                        // A connection dropped partway through the body is retried like one dropped before it
+                        Err(err) => (FetchError::Body(err), None),
+                    }
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    let retry_after = if status == StatusCode::TOO_MANY_REQUESTS {
+                        parse_retry_after(response.headers())
+                    } else {
+                        None
+                    };
+                    let message = api_error_message(response).await;
+                    (FetchError::Status { status, message }, retry_after)
+                }
+                Err(err) => (FetchError::Http(err), None),
+            };
+            drop(permit);
+
+            if attempt >= self.max_retries || !err.is_retryable() {
+                return Err(err);
+            }
+            let delay = retry_after.unwrap_or_else(|| self.retry_delay(attempt));
+            warn!("Request to {} failed, retrying in {:?}: {}", redact_url(uri), delay, err);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        self.jitter + self.retry_base_delay.saturating_mul(2u32.saturating_pow(attempt))
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct Character {
-    name: String,
-    race: String,
-    profession: String,
+enum Fetched<T> {
+    Changed { value: T, etag: Option<String> },
+    NotModified,
 }
 
-#[derive(EnumIter)]
-enum Storyline {
-    Core,
-    Season1,
-    Season2,
-    HeartOfThorns,
-    Season3,
-    PathOfFire,
-    Season4,
-    IcebroodSaga,
-    EndOfDragons,
-    SecretsOfTheObscure,
+// Retry-After is either a number of seconds or an HTTP date
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(std::time::SystemTime::now()).unwrap_or(Duration::ZERO))
 }
 
-impl Storyline {
-    const fn id(&self) -> &str {
-        match self {
-            Storyline::Core => "215AAA0F-CDAC-4F93-86DA-C155A99B5784",
-            Storyline::Season1 => "A49D0CD7-E725-4141-8E10-180F1CED7CAF",
-            Storyline::Season2 => "A515A1D3-4BD7-4594-AE30-2C5D05FF5960",
-            Storyline::HeartOfThorns => "B8901E58-DC9D-4525-ADB2-79C93593291E",
-            Storyline::Season3 => "09766A86-D88D-4DF2-9385-259E9A8CA583",
-            Storyline::PathOfFire => "EAB597C0-C484-4FD3-9430-31433BAC81B6",
-            Storyline::Season4 => "C22AFD21-667A-4AA8-8210-AC74EAEE58BB",
-            Storyline::IcebroodSaga => "EDCAE800-302A-4D9B-8331-3CC769ADA0B3",
-            Storyline::EndOfDragons => "D1B709AB-92B6-4EE9-8B40-2B7C628E5022",
-            Storyline::SecretsOfTheObscure => "AEE99452-D323-4ABB-8F49-D7C0A752CBD1",
-        }
+// Everything the generator needs from the GW2 API, so it can run against the network or canned data
+#[async_trait]
+trait Gw2Source: Send + Sync {
+    // None when there is no key to check
+    async fn token_info(&self) -> Result<Option<TokenInfo>, GenError>;
+    async fn characters(&self) -> Result<HashSet<String>, GenError>;
+    async fn character(&self, name: &str) -> Result<Character, GenError>;
+    async fn season(&self, id: &str) -> Result<Season, GenError>;
+    async fn season_ids(&self) -> Result<Vec<String>, GenError>;
+    // Advances progress once per batch fetched
+    async fn quests(&self, progress: &ProgressBar) -> Result<Vec<Quest>, GenError>;
+    async fn quests_by_id(&self, ids: &[u32], progress: &ProgressBar) -> Result<Vec<Quest>, GenError>;
+    async fn completed_quests(&self, name: &str) -> Result<HashSet<u32>, GenError>;
+    // None when there is no key to ask about
+    async fn account(&self) -> Result<Option<Account>, GenError>;
+    // None when the data doesn't come from the API
+    async fn build_id(&self) -> Result<Option<u32>, GenError>;
+    // Which account name came from, when there's more than one
+    fn account_of(&self, _name: &str) -> Option<String> {
+        None
     }
+}
 
-    const fn snake_case(&self) -> &str {
-        match self {
-            Storyline::Core => "core",
-            Storyline::Season1 => "season_1",
-            Storyline::Season2 => "season_2",
-            Storyline::HeartOfThorns => "heart_of_thorns",
-            Storyline::Season3 => "season_3",
-            Storyline::PathOfFire => "path_of_fire",
-            Storyline::Season4 => "season_4",
-            Storyline::IcebroodSaga => "icebrood_saga",
-            Storyline::EndOfDragons => "end_of_dragons",
-            Storyline::SecretsOfTheObscure => "secrets_of_the_obscure",
+// The first requests made with the key, where a rejection means the key itself is wrong rather than the network
+fn key_error(err: FetchError) -> GenError {
+    match err {
+        FetchError::Status { status, message } if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN =>
+            GenError::InvalidApiKey(message),
+        err => GenError::Fetch(err),
+    }
+}
+
+struct ApiSource {
+    client: Arc<RateLimitedReqwestClient>,
+    // Scheme and host, e.g. https://api.guildwars2.com
+    base_url: String,
+    api_key: String,
+    lang: Lang,
+    // Where completed quests are kept with their ETag between runs, None to always fetch them in full
+    completed_cache: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCompletion {
+    etag: String,
+    quests: BTreeSet<u32>,
+}
+
+impl ApiSource {
+    fn url(&self, path: &str) -> String {
+        format!("{}/v2/{}", self.base_url.trim_end_matches('/'), path)
+    }
+}
+
+#[async_trait]
+impl Gw2Source for ApiSource {
+    async fn token_info(&self) -> Result<Option<TokenInfo>, GenError> {
+        let uri = self.url(&format!("tokeninfo?access_token={}", self.api_key));
+        Ok(Some(self.client.get_json(&uri).await.map_err(key_error)?))
+    }
+
+    async fn account(&self) -> Result<Option<Account>, GenError> {
+        let uri = self.url(&format!("account?access_token={}", self.api_key));
+        Ok(Some(self.client.get_json(&uri).await.map_err(key_error)?))
+    }
+
+    async fn build_id(&self) -> Result<Option<u32>, GenError> {
+        let build: BuildInfo = self.client.get_json(&self.url("build")).await?;
+        Ok(Some(build.id))
+    }
+
+    async fn characters(&self) -> Result<HashSet<String>, GenError> {
+        let uri = self.url(&format!("characters?access_token={}", self.api_key));
+        self.client.get_json(&uri).await.map_err(key_error)
+    }
+
+    async fn character(&self, name: &str) -> Result<Character, GenError> {
+        let uri = self.url(&format!("characters/{}/core?access_token={}", name, self.api_key));
+        Ok(self.client.get_json(&uri).await?)
+    }
+
+    async fn season(&self, id: &str) -> Result<Season, GenError> {
+        let uri = self.url(&format!("stories/seasons/{}?lang={}", id, self.lang.code()));
+        Ok(self.client.get_json(&uri).await?)
+    }
+
+    async fn season_ids(&self) -> Result<Vec<String>, GenError> {
+        Ok(self.client.get_json(&self.url("stories/seasons")).await?)
+    }
+
+    async fn quests(&self, progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        let quest_ids = self.client.get_json::<Vec<u32>>(&self.url("quests")).await?;
+        self.quests_by_id(&quest_ids, progress).await
+    }
+
+    async fn quests_by_id(&self, ids: &[u32], progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        let mut tasks = FuturesUnordered::new();
+        let base = self.url(&format!("quests?lang={}&ids=", self.lang.code()));
+        for uri in batched_id_urls(&base, ids) {
+            let client = Arc::clone(&self.client);
+            tasks.push(tokio::spawn(async move { client.get_json::<Vec<Quest>>(&uri).await }));
+            // categories.extend(reqwest_client.get(uri).await.send().await.unwrap().json::<Vec<AchievementCategory>>().await.unwrap());
+        }
+
+        progress.set_length(tasks.len() as u64);
+        let mut quests = Vec::with_capacity(ids.len());
+        while let Some(finished_task) = tasks.next().await {
+            quests.extend(finished_task??);
+            progress.inc(1);
         }
+
+        Ok(quests)
     }
 
-    const fn default_weight(&self) -> u32 {
+    async fn completed_quests(&self, name: &str) -> Result<HashSet<u32>, GenError> {
+        let uri = self.url(&format!("characters/{}/quests?access_token={}", name, self.api_key));
+        let cache_path = self.completed_cache.as_ref().map(|dir| dir.join(format!("{}.json", name)));
+        // Completions only ever grow, so the ETag rather than the cache's age decides whether it's current
+        let cached = cache_path.as_deref().and_then(|path| load_cache::<CachedCompletion>(path, Duration::MAX));
+
+        match self.client.get_json_if_changed::<HashSet<u32>>(&uri, cached.as_ref().map(|cached| cached.etag.as_str())).await? {
+            Fetched::NotModified => {
+                debug!("{} completed quests are unchanged since the last run", name);
+                Ok(cached.map(|cached| cached.quests.into_iter().collect()).unwrap_or_default())
+            }
+            Fetched::Changed { value, etag } => {
+                if let (Some(path), Some(etag)) = (&cache_path, etag) {
+                    store_cache(path, &CachedCompletion { etag, quests: value.iter().copied().collect() });
+                }
+                Ok(value)
+            }
+        }
+    }
+}
+
+// Several accounts as one: the account a character is listed on fetches it, the first account everything public.
+// All of them share one client, so the rate limit covers the run as a whole rather than each key
+struct MultiAccountSource {
+    accounts: Vec<ApiSource>,
+    // From each key's tokeninfo name once token_info has run
+    labels: Mutex<Vec<String>>,
+    // Index into accounts for each character, once characters has run
+    owners: Mutex<HashMap<String, usize>>,
+}
+
+impl MultiAccountSource {
+    fn new(accounts: Vec<ApiSource>) -> Self {
+        let labels = (1..=accounts.len()).map(|number| format!("account {}", number)).collect();
+        MultiAccountSource { accounts, labels: Mutex::new(labels), owners: Mutex::new(HashMap::new()) }
+    }
+
+    fn owner(&self, name: &str) -> &ApiSource {
+        let index = self.owners.lock().expect("owners are never poisoned").get(name).copied().unwrap_or(0);
+        &self.accounts[index]
+    }
+
+    fn label(&self, index: usize) -> String {
+        self.labels.lock().expect("labels are never poisoned")[index].clone()
+    }
+}
+
+#[async_trait]
+impl Gw2Source for MultiAccountSource {
+    // Only the permissions every key has, so a key missing one fails the run
+    async fn token_info(&self) -> Result<Option<TokenInfo>, GenError> {
+        let mut combined: Option<TokenInfo> = None;
+        for (index, account) in self.accounts.iter().enumerate() {
+            let Some(token_info) = account.token_info().await? else {
+                continue;
+            };
+            if !token_info.name.is_empty() {
+                self.labels.lock().expect("labels are never poisoned")[index] = token_info.name.clone();
+            }
+            combined = Some(match combined {
+                Some(mut combined) => {
+                    combined.permissions.retain(|permission| token_info.permissions.contains(permission));
+                    combined
+                }
+                None => token_info,
+            });
+        }
+        Ok(combined)
+    }
+
+    // Only the first key's account, the one the output is named for
+    async fn account(&self) -> Result<Option<Account>, GenError> {
+        self.accounts[0].account().await
+    }
+
+    async fn build_id(&self) -> Result<Option<u32>, GenError> {
+        self.accounts[0].build_id().await
+    }
+
+    // Character names are unique across the game, so a name on two accounts means a key listed twice
+    async fn characters(&self) -> Result<HashSet<String>, GenError> {
+        let mut characters = HashSet::new();
+        for (index, account) in self.accounts.iter().enumerate() {
+            for name in account.characters().await? {
+                let mut owners = self.owners.lock().expect("owners are never poisoned");
+                if let Some(&owner) = owners.get(&name) {
+                    warn!("{} is on both {} and {}, using {}; is the same key listed twice?",
+                          name, self.label(owner), self.label(index), self.label(owner));
+                    continue;
+                }
+                owners.insert(name.clone(), index);
+                characters.insert(name);
+            }
+        }
+        Ok(characters)
+    }
+
+    async fn character(&self, name: &str) -> Result<Character, GenError> {
+        self.owner(name).character(name).await
+    }
+
+    async fn season(&self, id: &str) -> Result<Season, GenError> {
+        self.accounts[0].season(id).await
+    }
+
+    async fn season_ids(&self) -> Result<Vec<String>, GenError> {
+        self.accounts[0].season_ids().await
+    }
+
+    async fn quests(&self, progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        self.accounts[0].quests(progress).await
+    }
+
+    async fn quests_by_id(&self, ids: &[u32], progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        self.accounts[0].quests_by_id(ids, progress).await
+    }
+
+    async fn completed_quests(&self, name: &str) -> Result<HashSet<u32>, GenError> {
+        self.owner(name).completed_quests(name).await
+    }
+
+    fn account_of(&self, name: &str) -> Option<String> {
+        let owner = self.owners.lock().expect("owners are never poisoned").get(name).copied();
+        owner.map(|index| self.label(index))
+    }
+}
+
+// The API takes at most this many ids per request
+const MAX_IDS_PER_REQUEST: usize = 100;
+// Stay well under the 8KB or so that servers and proxies commonly cut off at
+const MAX_URL_LENGTH: usize = 2000;
+
+// Splits ids into comma separated batches appended to base, starting a new batch whenever
+// the next id would go over either limit
+fn batched_id_urls(base: &str, ids: &[u32]) -> Vec<String> {
+    let mut uris = Vec::new();
+    let mut uri = base.to_string();
+    let mut batched = 0;
+    for id in ids {
+        let id = id.to_string();
+        if batched == MAX_IDS_PER_REQUEST || (batched > 0 && uri.len() + 1 + id.len() > MAX_URL_LENGTH) {
+            uris.push(std::mem::replace(&mut uri, base.to_string()));
+            batched = 0;
+        }
+        if batched > 0 {
+            uri.push(',');
+        }
+        uri.push_str(&id);
+        batched += 1;
+    }
+    if batched > 0 {
+        uris.push(uri);
+    }
+    uris
+}
+
+// JSON fixtures laid out like the API: characters.json, characters/<name>/quests.json,
+// seasons/<id>.json and quests.json
+struct FixtureSource {
+    dir: PathBuf,
+}
+
+fn read_fixture<T>(path: PathBuf) -> Result<T, GenError> where T: DeserializeOwned {
+    let file = File::open(&path).map_err(|err| GenError::Read(path.clone(), err))?;
+    serde_json::from_reader(BufReader::new(file)).map_err(|err| GenError::InputParse(path, err.into()))
+}
+
+#[async_trait]
+impl Gw2Source for FixtureSource {
+    async fn token_info(&self) -> Result<Option<TokenInfo>, GenError> {
+        Ok(None)
+    }
+
+    async fn account(&self) -> Result<Option<Account>, GenError> {
+        Ok(None)
+    }
+
+    async fn build_id(&self) -> Result<Option<u32>, GenError> {
+        Ok(None)
+    }
+
+    async fn characters(&self) -> Result<HashSet<String>, GenError> {
+        let characters: Vec<Character> = read_fixture(self.dir.join("characters.json"))?;
+        Ok(characters.into_iter().map(|character| character.name).collect())
+    }
+
+    async fn character(&self, name: &str) -> Result<Character, GenError> {
+        let path = self.dir.join("characters.json");
+        let characters: Vec<Character> = read_fixture(path.clone())?;
+        characters.into_iter().find(|character| character.name == name).ok_or_else(|| {
+            GenError::Config(format!("{} has no entry for {}", path.display(), name))
+        })
+    }
+
+    async fn season(&self, id: &str) -> Result<Season, GenError> {
+        read_fixture(self.dir.join("seasons").join(format!("{}.json", id)))
+    }
+
+    async fn season_ids(&self) -> Result<Vec<String>, GenError> {
+        let dir = self.dir.join("seasons");
+        let entries = fs::read_dir(&dir).map_err(|err| GenError::Read(dir.clone(), err))?;
+        let mut ids = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|err| GenError::Read(dir.clone(), err))?.path();
+            if path.extension().is_some_and(|extension| extension == "json") {
+                if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    async fn quests(&self, _progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        read_fixture(self.dir.join("quests.json"))
+    }
+
+    async fn quests_by_id(&self, ids: &[u32], progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        let quests = self.quests(progress).await?;
+        Ok(quests.into_iter().filter(|quest| ids.contains(&quest.id)).collect())
+    }
+
+    async fn completed_quests(&self, name: &str) -> Result<HashSet<u32>, GenError> {
+        read_fixture(self.dir.join("characters").join(name).join("quests.json"))
+    }
+}
+
+// Everything one run fetched, so --snapshot can replay it exactly
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    characters: BTreeSet<String>,
+    character_cores: BTreeMap<String, Character>,
+    seasons: BTreeMap<String, Season>,
+    // Only recorded by --include-future-storylines
+    #[serde(default)]
+    season_ids: Option<Vec<String>>,
+    // Only recorded by --scale-by-daily-monthly-ap
+    #[serde(default)]
+    account: Option<Account>,
+    // Only recorded by --stamp
+    #[serde(default)]
+    build_id: Option<u32>,
+    quests: BTreeMap<u32, Quest>,
+    // Whether quests holds every quest, rather than only completed ones
+    all_quests: bool,
+    completed_quests: BTreeMap<String, BTreeSet<u32>>,
+}
+
+// Replays a snapshot file, or passes requests through to recording and records what comes back
+struct SnapshotSource {
+    path: PathBuf,
+    recording: Option<Arc<dyn Gw2Source>>,
+    snapshot: Mutex<Snapshot>,
+}
+
+impl SnapshotSource {
+    fn replay(path: PathBuf) -> Result<Self, GenError> {
+        let snapshot = read_fixture(path.clone())?;
+        Ok(SnapshotSource { path, recording: None, snapshot: Mutex::new(snapshot) })
+    }
+
+    fn record(path: PathBuf, source: Arc<dyn Gw2Source>) -> Self {
+        SnapshotSource { path, recording: Some(source), snapshot: Mutex::new(Snapshot::default()) }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Snapshot> {
+        self.snapshot.lock().expect("snapshot is never poisoned")
+    }
+
+    // Missing entries look like a 404, so they're handled the same way as on the live API
+    fn not_recorded(&self, what: String) -> GenError {
+        let err = std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is not in the snapshot", what));
+        GenError::Read(self.path.clone(), err)
+    }
+
+    fn save(&self) -> Result<(), GenError> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|err| GenError::File(parent.to_path_buf(), err))?;
+        }
+        write_atomically(&self.path, |file| Ok(serde_json::to_writer(file, &*self.lock())?))
+    }
+}
+
+#[async_trait]
+impl Gw2Source for SnapshotSource {
+    async fn token_info(&self) -> Result<Option<TokenInfo>, GenError> {
+        match &self.recording {
+            Some(source) => source.token_info().await,
+            None => Ok(None),
+        }
+    }
+
+    async fn account(&self) -> Result<Option<Account>, GenError> {
+        let Some(source) = &self.recording else {
+            return self.lock().account.clone().map(Some).ok_or_else(|| self.not_recorded("the account".to_string()));
+        };
+        let account = source.account().await?;
+        self.lock().account = account.clone();
+        Ok(account)
+    }
+
+    // Only ever stamped into the output, so an older snapshot without one still replays
+    async fn build_id(&self) -> Result<Option<u32>, GenError> {
+        let Some(source) = &self.recording else {
+            return Ok(self.lock().build_id);
+        };
+        let build_id = source.build_id().await?;
+        self.lock().build_id = build_id;
+        Ok(build_id)
+    }
+
+    fn account_of(&self, name: &str) -> Option<String> {
+        self.recording.as_ref().and_then(|source| source.account_of(name))
+    }
+
+    async fn characters(&self) -> Result<HashSet<String>, GenError> {
+        let Some(source) = &self.recording else {
+            return Ok(self.lock().characters.iter().cloned().collect());
+        };
+        let characters = source.characters().await?;
+        self.lock().characters = characters.iter().cloned().collect();
+        Ok(characters)
+    }
+
+    async fn character(&self, name: &str) -> Result<Character, GenError> {
+        let Some(source) = &self.recording else {
+            return self.lock().character_cores.get(name).cloned()
+                .ok_or_else(|| self.not_recorded(format!("character {}", name)));
+        };
+        let character = source.character(name).await?;
+        self.lock().character_cores.insert(name.to_string(), character.clone());
+        Ok(character)
+    }
+
+    async fn season(&self, id: &str) -> Result<Season, GenError> {
+        let Some(source) = &self.recording else {
+            return self.lock().seasons.get(id).cloned().ok_or_else(|| self.not_recorded(format!("season {}", id)));
+        };
+        let season = source.season(id).await?;
+        self.lock().seasons.insert(id.to_string(), season.clone());
+        Ok(season)
+    }
+
+    async fn season_ids(&self) -> Result<Vec<String>, GenError> {
+        let Some(source) = &self.recording else {
+            return self.lock().season_ids.clone().ok_or_else(|| self.not_recorded("the season list".to_string()));
+        };
+        let ids = source.season_ids().await?;
+        self.lock().season_ids = Some(ids.clone());
+        Ok(ids)
+    }
+
+    async fn quests(&self, progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        let Some(source) = &self.recording else {
+            let snapshot = self.lock();
+            if !snapshot.all_quests {
+                return Err(GenError::Config(format!(
+                    "{} only has completed quests, record it again without --limit-quests-detail", self.path.display())));
+            }
+            return Ok(snapshot.quests.values().cloned().collect());
+        };
+        let quests = source.quests(progress).await?;
+        let mut snapshot = self.lock();
+        snapshot.quests.extend(quests.iter().map(|quest| (quest.id, quest.clone())));
+        snapshot.all_quests = true;
+        Ok(quests)
+    }
+
+    async fn quests_by_id(&self, ids: &[u32], progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+        let Some(source) = &self.recording else {
+            return Ok(ids.iter().filter_map(|id| self.lock().quests.get(id).cloned()).collect());
+        };
+        let quests = source.quests_by_id(ids, progress).await?;
+        self.lock().quests.extend(quests.iter().map(|quest| (quest.id, quest.clone())));
+        Ok(quests)
+    }
+
+    async fn completed_quests(&self, name: &str) -> Result<HashSet<u32>, GenError> {
+        let Some(source) = &self.recording else {
+            return self.lock().completed_quests.get(name).map(|completed| completed.iter().copied().collect())
+                .ok_or_else(|| self.not_recorded(format!("completed quests for {}", name)));
+        };
+        let completed = source.completed_quests(name).await?;
+        self.lock().completed_quests.insert(name.to_string(), completed.iter().copied().collect());
+        Ok(completed)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum OptionValue{
+    Value(String),
+    Number(u32),
+    Table(BTreeMap<String, u32>),
+    // For options that take ranges or several choices, nothing generates one yet
+    List(Vec<OptionValue>),
+}
+
+#[derive(Debug)]
+enum OptionValueError {
+    // Weighted values only go into a Table, named is the variant that was found instead
+    NotATable(&'static str),
+}
+
+impl fmt::Display for OptionValueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Storyline::Core => 1,
-            Storyline::Season1 => 2,
-            Storyline::Season2 => 4,
-            Storyline::HeartOfThorns => 8,
-            Storyline::Season3 => 16,
-            Storyline::PathOfFire => 32,
-            Storyline::Season4 => 64,
-            Storyline::IcebroodSaga => 128,
-            Storyline::EndOfDragons => 256,
-            Storyline::SecretsOfTheObscure => 512,
+            OptionValueError::NotATable(found) => write!(f, "can only insert a weighted value into a table, not a {}", found),
         }
     }
+}
 
-    const fn max_quests(&self) -> usize {
+impl std::error::Error for OptionValueError {}
+
+impl OptionValue {
+    fn insert(&mut self, value: String, weight: u32) -> Result<Option<u32>, OptionValueError> {
         match self {
-            Storyline::Core => 49,
-            Storyline::Season1 => 30,
-            Storyline::Season2 => 32,
-            Storyline::HeartOfThorns => 16,
-            Storyline::Season3 => 36,
-            Storyline::PathOfFire => 16,
-            Storyline::Season4 => 30,
-            Storyline::IcebroodSaga => 41,
-            Storyline::EndOfDragons => 27,
-            Storyline::SecretsOfTheObscure => 20,
+            OptionValue::Table(map) => Ok(map.insert(value, weight)),
+            OptionValue::Value(_) => Err(OptionValueError::NotATable("value")),
+            OptionValue::Number(_) => Err(OptionValueError::NotATable("number")),
+            OptionValue::List(_) => Err(OptionValueError::NotATable("list")),
         }
     }
+}
+
+// Told apart by the shape of the data, so a quoted "5" stays a Value and a bare 5 becomes a Number
+impl<'de> Deserialize<'de> for OptionValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        struct OptionValueVisitor;
+
+        impl<'de> Visitor<'de> for OptionValueVisitor {
+            type Value = OptionValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "a value, a whole number, a table of weights or a list")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<OptionValue, E> where E: serde::de::Error {
+                Ok(OptionValue::Value(value.to_string()))
+            }
+
+            fn visit_bool<E>(self, value: bool) -> Result<OptionValue, E> where E: serde::de::Error {
+                Ok(OptionValue::Value(value.to_string()))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<OptionValue, E> where E: serde::de::Error {
+                u32::try_from(value).map(OptionValue::Number)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Unsigned(value), &self))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<OptionValue, E> where E: serde::de::Error {
+                u32::try_from(value).map(OptionValue::Number)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(value), &self))
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<OptionValue, A::Error> where A: MapAccess<'de> {
+                let mut table = BTreeMap::new();
+                while let Some((OptionKey(key), weight)) = map.next_entry::<OptionKey, u32>()? {
+                    table.insert(key, weight);
+                }
+                Ok(OptionValue::Table(table))
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<OptionValue, A::Error> where A: serde::de::SeqAccess<'de> {
+                let mut list = Vec::new();
+                while let Some(value) = seq.next_element()? {
+                    list.push(value);
+                }
+                Ok(OptionValue::List(list))
+            }
+        }
+
+        deserializer.deserialize_any(OptionValueVisitor)
+    }
+}
+
+// Yaml reads keys like true or 5 as a bool or number, while Table keys are always strings
+struct OptionKey(String);
+
+impl<'de> Deserialize<'de> for OptionKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        struct OptionKeyVisitor;
+
+        impl<'de> Visitor<'de> for OptionKeyVisitor {
+            type Value = OptionKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "an option value")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<OptionKey, E> where E: serde::de::Error {
+                Ok(OptionKey(value.to_string()))
+            }
+
+            fn visit_bool<E>(self, value: bool) -> Result<OptionKey, E> where E: serde::de::Error {
+                Ok(OptionKey(value.to_string()))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<OptionKey, E> where E: serde::de::Error {
+                Ok(OptionKey(value.to_string()))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<OptionKey, E> where E: serde::de::Error {
+                Ok(OptionKey(value.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(OptionKeyVisitor)
+    }
+}
+
+impl Serialize for OptionValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        match self {
+            OptionValue::Value(val) => {val.serialize(serializer)}
+            OptionValue::Number(number) => {number.serialize(serializer)}
+            OptionValue::Table(table) => {table.serialize(serializer)}
+            OptionValue::List(list) => {list.serialize(serializer)}
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Trigger {
+    option_category: String, // Always GAME_NAME
+    option_name: String,
+    option_result: String,
+    options: BTreeMap<String, BTreeMap<String, OptionValue>>,
+}
+
+impl Trigger {
+    fn new(option_name: String, option_result: String) -> Self {
+        Trigger {
+            option_category: GAME_NAME.to_string(),
+            option_name,
+            option_result,
+            options: BTreeMap::from([(GAME_NAME.to_string(), BTreeMap::new())]),
+        }
+    }
+
+    fn game_options_mut(&mut self) -> &mut BTreeMap<String, OptionValue> {
+        self.options.entry(GAME_NAME.to_string()).or_default()
+    }
+
+    fn with_game_option(&mut self, name: &str, value: OptionValue) -> &mut Self {
+        self.game_options_mut().insert(name.to_string(), value);
+        self
+    }
+
+    // Starts an empty table when the option isn't set yet
+    fn game_option_table_mut(&mut self, name: &str) -> &mut OptionValue {
+        self.game_options_mut().entry(name.to_string()).or_insert_with(|| OptionValue::Table(BTreeMap::new()))
+    }
+}
+
+// Archipelago merges triggers that share an option_name and option_result, so those have to be unique
+fn find_trigger_collisions(triggers: &[Trigger]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut collisions = Vec::new();
+    for trigger in triggers {
+        let key = (&trigger.option_name, &trigger.option_result);
+        if !seen.insert(key) {
+            collisions.push(format!("{}: {}", trigger.option_name, trigger.option_result));
+        }
+    }
+    collisions
+}
+
+// Debug prints a placeholder so dumping the input never shows the key
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+struct ApiKey(String);
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"***\"")
+    }
+}
+
+// api_key takes one key, or a list to generate across several accounts
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum ApiKeys {
+    One(ApiKey),
+    Many(Vec<ApiKey>),
+}
+
+impl ApiKeys {
+    fn keys(&self) -> Vec<String> {
+        match self {
+            ApiKeys::One(key) => vec![key.0.clone()],
+            ApiKeys::Many(keys) => keys.iter().map(|key| key.0.clone()).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct Input {
+    #[serde(default)]
+    api_key: Option<ApiKeys>,
+    #[serde(deserialize_with = "unique_characters")]
+    characters: HashMap<String, CharacterInput>,
+    #[serde(default = "default_mist_fragments_required")]
+    mist_fragments_required: u32,
+    #[serde(default = "default_extra_mist_fragment_percent")]
+    extra_mist_fragment_percent: u32,
+    // Weight given to the profession and race read from the API in each character's trigger
+    #[serde(default = "default_weight")]
+    profession_weight: u32,
+    #[serde(default = "default_weight")]
+    race_weight: u32,
+    // Replace the fixed value each of these tables rolls, keeping its random variants
+    achievement_weight: Option<u32>,
+    quest_weight: Option<u32>,
+    training_weight: Option<u32>,
+    world_boss_weight: Option<u32>,
+    player_name: Option<String>,
+    description: Option<String>,
+    // Merged over the default weight tables, keyed by OutputOptions field name
+    #[serde(default)]
+    overrides: HashMap<String, HashMap<String, u32>>,
+    // One output per entry, each falling back to the settings above
+    #[serde(default)]
+    players: Vec<PlayerInput>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct PlayerInput {
+    player_name: Option<String>,
+    description: Option<String>,
+    #[serde(default, deserialize_with = "optional_unique_characters")]
+    characters: Option<HashMap<String, CharacterInput>>,
+}
+
+// A plain HashMap keeps the last of duplicate keys, which hides a character pasted twice
+struct CharactersVisitor;
+
+impl<'de> Visitor<'de> for CharactersVisitor {
+    type Value = HashMap<String, CharacterInput>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a map of character names")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error> where A: MapAccess<'de> {
+        let mut characters = HashMap::new();
+        while let Some((name, mut character)) = map.next_entry::<String, CharacterInput>()? {
+            if characters.contains_key(&name) {
+                return Err(serde::de::Error::custom(format!("duplicate character {}", name)));
+            }
+            character.order = characters.len();
+            characters.insert(name, character);
+        }
+        Ok(characters)
+    }
+}
+
+fn unique_characters<'de, D>(deserializer: D) -> Result<HashMap<String, CharacterInput>, D::Error>
+    where D: serde::Deserializer<'de> {
+    deserializer.deserialize_map(CharactersVisitor)
+}
+
+fn optional_unique_characters<'de, D>(deserializer: D) -> Result<Option<HashMap<String, CharacterInput>>, D::Error>
+    where D: serde::Deserializer<'de> {
+    #[derive(Deserialize)]
+    struct UniqueCharacters(#[serde(deserialize_with = "unique_characters")] HashMap<String, CharacterInput>);
+    Ok(Option::<UniqueCharacters>::deserialize(deserializer)?.map(|characters| characters.0))
+}
+
+impl Input {
+    // Expands `players` into a complete input per player, labelled for naming its output file
+    fn player_inputs(&self) -> Vec<(String, Input)> {
+        if self.players.is_empty() {
+            return vec![("gw2".to_string(), self.clone())];
+        }
+
+        self.players.iter().enumerate().map(|(index, player)| {
+            let mut input = self.clone();
+            input.players = Vec::new();
+            if let Some(player_name) = &player.player_name {
+                input.player_name = Some(player_name.clone());
+            }
+            if let Some(description) = &player.description {
+                input.description = Some(description.clone());
+            }
+            if let Some(characters) = &player.characters {
+                input.characters = characters.clone();
+            }
+
+            let label = match &input.player_name {
+                Some(name) if !name.contains('{') => name.replace(['/', '\\'], "_"),
+                _ => format!("player_{}", index + 1),
+            };
+            (label, input)
+        }).collect()
+    }
+}
+
+const fn default_mist_fragments_required() -> u32 {10}
+const fn default_extra_mist_fragment_percent() -> u32 {50}
+
+
+const fn default_weight() -> u32 {50}
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct CharacterInput {
+    // The character table's weight for this character; its trigger only runs once it's rolled and has no weight
+    #[serde(default = "default_weight")]
+    weight: u32,
+    storyline: Option<HashMap<String, u32>>,
+    // Replace the single API-derived profession/race in this character's trigger
+    profession: Option<HashMap<String, u32>>,
+    race: Option<HashMap<String, u32>>,
+    // Replace the global tables when this character is rolled
+    heal_skill: Option<HashMap<String, u32>>,
+    gear_slots: Option<HashMap<String, u32>>,
+    include_competitive: Option<HashMap<String, u32>>,
+    group_content: Option<HashMap<String, u32>>,
+    // Not created yet, so profession and race are randomized without warning
+    #[serde(default)]
+    new: bool,
+    // Position in the input's characters, set while reading them for --sort-characters input
+    #[serde(skip)]
+    order: usize,
+}
+
+impl Default for CharacterInput {
+    fn default() -> Self {
+        CharacterInput {
+            weight: default_weight(),
+            storyline: None,
+            profession: None,
+            race: None,
+            heal_skill: None,
+            gear_slots: None,
+            include_competitive: None,
+            group_content: None,
+            new: false,
+            // Not listed in the input, e.g. added by --all-characters, so after every listed character
+            order: usize::MAX,
+        }
+    }
+}
+
+// The apworld's registered game name, which Archipelago matches exactly
+const GAME_NAME: &str = "Guild Wars 2";
+
+#[derive(Debug, PartialEq)]
+struct Output {
+    name: String,
+    description: String,
+    game: String,
+    // Serialized under GAME_NAME
+    game_options: OutputOptions
+
+}
+
+// serde's rename only takes a literal, so the game options key is written by hand to come from GAME_NAME
+impl Serialize for Output {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut output = serializer.serialize_struct("Output", 4)?;
+        output.serialize_field("name", &self.name)?;
+        output.serialize_field("description", &self.description)?;
+        output.serialize_field("game", &self.game)?;
+        output.serialize_field(GAME_NAME, &self.game_options)?;
+        output.end()
+    }
+}
+
+// By hand for the same reason as Serialize, ignoring any other top level keys
+impl<'de> Deserialize<'de> for Output {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error> where D: serde::Deserializer<'de> {
+        struct OutputVisitor;
+
+        impl<'de> Visitor<'de> for OutputVisitor {
+            type Value = Output;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "an Archipelago player yaml")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Output, A::Error> where A: MapAccess<'de> {
+                let (mut name, mut description, mut game, mut game_options) = (None, None, None, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "name" => name = Some(map.next_value()?),
+                        "description" => description = Some(map.next_value()?),
+                        "game" => game = Some(map.next_value()?),
+                        GAME_NAME => game_options = Some(map.next_value()?),
+                        _ => {
+                            map.next_value::<serde::de::IgnoredAny>()?;
+                        }
+                    }
+                }
+                Ok(Output {
+                    name: name.ok_or_else(|| serde::de::Error::missing_field("name"))?,
+                    description: description.ok_or_else(|| serde::de::Error::missing_field("description"))?,
+                    game: game.ok_or_else(|| serde::de::Error::missing_field("game"))?,
+                    game_options: game_options.ok_or_else(|| serde::de::Error::missing_field(GAME_NAME))?,
+                })
+            }
+        }
+
+        deserializer.deserialize_map(OutputVisitor)
+    }
+}
+
+impl Output {
+    fn new() -> Self {
+        Self {
+            name: "Player{number}".to_string(),
+            description: "Customized Guild Wars 2 Template".to_string(),
+            game: GAME_NAME.to_string(),
+            game_options: OutputOptions::new(),
+        }
+    }
+}
+
+fn check_weights(problems: &mut Vec<String>, name: String, weights: &HashMap<String, u32>) {
+    if weights.values().all(|weight| *weight == 0) {
+        problems.push(format!("every weight in {} is 0", name));
+    }
+}
+
+// Collects every weight table that can't roll anything and every unknown storyline name
+fn validate_input(input: &Input) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if !input.characters.is_empty() {
+        let weights = input.characters.iter()
+            .map(|(name, character)| (name.clone(), character.weight))
+            .collect();
+        check_weights(&mut problems, "characters".to_string(), &weights);
+    }
+    if input.profession_weight == 0 {
+        problems.push("profession_weight must be above 0".to_string());
+    }
+    if input.race_weight == 0 {
+        problems.push("race_weight must be above 0".to_string());
+    }
+    // Overrides are merged over the defaults, so it's the merged table that has to be able to roll
+    let mut defaults = OutputOptions::default();
+    for (option_name, weights) in &input.overrides {
+        match defaults.weight_table_mut(option_name) {
+            Some(table) => {
+                table.extend(weights.iter().map(|(value, weight)| (value.clone(), *weight)));
+                if table.values().all(|weight| *weight == 0) {
+                    problems.push(format!("every weight in overrides.{} is 0, defaults included", option_name));
+                }
+            }
+            None => check_weights(&mut problems, format!("overrides.{}", option_name), weights),
+        }
+    }
+
+    let mut character_names: Vec<_> = input.characters.keys().collect();
+    character_names.sort();
+    for name in character_names {
+        let character = &input.characters[name];
+        if let Some(storylines) = &character.storyline {
+            check_weights(&mut problems, format!("{}.storyline", name), storylines);
+            for storyline_name in storylines.keys() {
+                if !Storyline::iter().any(|storyline| storyline.snake_case() == storyline_name)
+                    && !STORYLINE_GROUPS.iter().any(|(group, _)| group == storyline_name) {
+                    problems.push(format!("{}.storyline has unknown storyline {}", name, storyline_name));
+                }
+            }
+        }
+        if let Some(professions) = &character.profession {
+            check_weights(&mut problems, format!("{}.profession", name), professions);
+        }
+        if let Some(races) = &character.race {
+            check_weights(&mut problems, format!("{}.race", name), races);
+        }
+        for (option_name, weights) in [("heal_skill", &character.heal_skill),
+                                       ("gear_slots", &character.gear_slots),
+                                       ("include_competitive", &character.include_competitive),
+                                       ("group_content", &character.group_content)] {
+            if let Some(weights) = weights {
+                check_weights(&mut problems, format!("{}.{}", name, option_name), weights);
+            }
+        }
+    }
+
+    problems
+}
+
+// Archipelago caps slot names at 16 characters once its {number}/{player} placeholders are filled in
+fn validate_player_name(name: &str) -> Result<(), GenError> {
+    if name.trim().is_empty() || name.trim() != name {
+        return Err(GenError::Config(format!("player_name {:?} can't be blank or start or end with spaces", name)));
+    }
+    let expanded = ["{number}", "{NUMBER}", "{player}", "{PLAYER}"].iter()
+        .fold(name.to_string(), |name, placeholder| name.replace(placeholder, "1"));
+    if expanded.chars().count() > 16 {
+        return Err(GenError::Config(format!("player_name {:?} is longer than 16 characters", name)));
+    }
+    Ok(())
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        let mut val = Self::new();
+        val.game_options = OutputOptions::default();
+
+        val
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct OutputOptions {
+    progression_balancing: BTreeMap<String, u32>,
+    accessibility: BTreeMap<String, u32>,
+    character: BTreeMap<String, u32>,
+    triggers: Vec<Trigger>,
+    character_profession: BTreeMap<String, u32>,
+    character_race: BTreeMap<String, u32>,
+    starting_mainhand_weapon: BTreeMap<String, u32>,
+    starting_offhand_weapon: BTreeMap<String, u32>,
+    group_content: BTreeMap<String, u32>,
+    include_competitive: BTreeMap<String, u32>,
+    achievement_weight: BTreeMap<String, u32>,
+    quest_weight: BTreeMap<String, u32>,
+    training_weight: BTreeMap<String, u32>,
+    world_boss_weight: BTreeMap<String, u32>,
+    unique_item_weight: BTreeMap<String, u32>,
+    poi_weight: BTreeMap<String, u32>,
+    storyline: BTreeMap<String, u32>,
+    mist_fragments_required: u32,
+    extra_mist_fragment_percent: u32,
+    heal_skill: BTreeMap<String, u32>,
+    gear_slots: BTreeMap<String, u32>,
+    storyline_items: BTreeMap<String, u32>,
+}
+
+impl OutputOptions {
+    fn new() -> Self {
+        OutputOptions {
+            progression_balancing: BTreeMap::new(),
+            accessibility: BTreeMap::new(),
+            character: BTreeMap::new(),
+            triggers: Vec::new(),
+            character_profession: BTreeMap::new(),
+            character_race: BTreeMap::new(),
+            starting_mainhand_weapon: BTreeMap::new(),
+            starting_offhand_weapon: BTreeMap::new(),
+            group_content: BTreeMap::new(),
+            include_competitive: BTreeMap::new(),
+            achievement_weight: BTreeMap::new(),
+            quest_weight: BTreeMap::new(),
+            training_weight: BTreeMap::new(),
+            world_boss_weight: BTreeMap::new(),
+            unique_item_weight: BTreeMap::new(),
+            poi_weight: BTreeMap::new(),
+            storyline: BTreeMap::new(),
+            mist_fragments_required: default_mist_fragments_required(),
+            extra_mist_fragment_percent: default_extra_mist_fragment_percent(),
+            heal_skill: BTreeMap::new(),
+            gear_slots: BTreeMap::new(),
+            storyline_items: BTreeMap::new(),
+        }
+    }
+}
+
+const WEIGHT_TABLES: [&str; 19] = [
+    "progression_balancing", "accessibility", "character", "character_profession", "character_race",
+    "starting_mainhand_weapon", "starting_offhand_weapon", "group_content", "include_competitive",
+    "achievement_weight", "quest_weight", "training_weight", "world_boss_weight", "unique_item_weight",
+    "poi_weight", "storyline", "heal_skill", "gear_slots", "storyline_items",
+];
+
+// Option names and values the GW2 apworld accepts, keep in step with it
+const OPTION_SCHEMA: &str = include_str!("../schema/gw2_options.json");
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum OptionSchema {
+    Choice { values: HashSet<String> },
+    // Any number, or one of the named values
+    Range { values: HashSet<String> },
+    Number,
+    // Free form, like character names
+    Any,
+}
+
+impl OptionSchema {
+    // Archipelago reads option values case-insensitively
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            OptionSchema::Choice { values } => values.contains(&value.to_lowercase()),
+            OptionSchema::Range { values } => value.parse::<i64>().is_ok() || values.contains(&value.to_lowercase()),
+            OptionSchema::Number => value.parse::<i64>().is_ok(),
+            OptionSchema::Any => true,
+        }
+    }
+}
+
+// Checks every option name and value in the output against OPTION_SCHEMA, returning what it doesn't recognize
+fn validate_output(options: &OutputOptions) -> Result<Vec<String>, GenError> {
+    let schema: HashMap<String, OptionSchema> = serde_json::from_str(OPTION_SCHEMA)?;
+
+    // Triggers can set an option to a value that only exists for another trigger to match on
+    let mut trigger_options = Vec::new();
+    for trigger in &options.triggers {
+        for trigger_option in trigger.options.values() {
+            for (name, value) in trigger_option {
+                trigger_options.push((trigger, name, serde_json::to_value(value)?));
+            }
+        }
+    }
+    let matched: HashSet<(&str, &str)> = options.triggers.iter()
+        .map(|trigger| (trigger.option_name.as_str(), trigger.option_result.as_str()))
+        .collect();
+    let mut set_by_triggers = HashSet::new();
+    for (_, name, value) in &trigger_options {
+        match value {
+            serde_json::Value::String(value) => { set_by_triggers.insert((name.as_str(), value.clone())); }
+            serde_json::Value::Object(table) =>
+                set_by_triggers.extend(table.keys().map(|key| (name.as_str(), key.clone()))),
+            _ => {}
+        }
+    }
+
+    let mut problems = Vec::new();
+    let mut check = |location: String, name: &str, value: &serde_json::Value| {
+        let Some(option) = schema.get(name) else {
+            problems.push(format!("{} is not a {} option", location, GAME_NAME));
+            return;
+        };
+        let values: Vec<String> = match value {
+            serde_json::Value::Object(table) => table.keys().cloned().collect(),
+            serde_json::Value::Array(list) => list.iter().map(|value| value.to_string().trim_matches('"').to_string()).collect(),
+            serde_json::Value::String(value) => vec![value.clone()],
+            value => vec![value.to_string()],
+        };
+        for value in values {
+            if !option.accepts(&value) && !matched.contains(&(name, value.as_str())) {
+                problems.push(format!("{} has unknown value {:?}", location, value));
+            }
+        }
+    };
+
+    if let serde_json::Value::Object(top_level) = serde_json::to_value(options)? {
+        for (name, value) in top_level.iter().filter(|(name, _)| *name != "triggers") {
+            check(name.clone(), name, value);
+        }
+    }
+    for (trigger, name, value) in &trigger_options {
+        check(format!("trigger {} {} option {}", trigger.option_name, trigger.option_result, name), name, value);
+    }
+    for trigger in &options.triggers {
+        let location = format!("trigger {} {}", trigger.option_name, trigger.option_result);
+        for category in trigger.options.keys().filter(|category| *category != GAME_NAME) {
+            problems.push(format!("{} sets options for {}", location, category));
+        }
+        match schema.get(&trigger.option_name) {
+            None => problems.push(format!("{} matches on unknown option {}", location, trigger.option_name)),
+            Some(option) => {
+                let set_by_trigger = set_by_triggers.contains(&(trigger.option_name.as_str(), trigger.option_result.clone()));
+                if !option.accepts(&trigger.option_result) && !set_by_trigger {
+                    problems.push(format!("{} matches on a value {} can never have", location, trigger.option_name));
+                }
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+// Swaps the numeric entries of a range table for value, which takes over their combined weight
+fn replace_fixed_value(table: &mut BTreeMap<String, u32>, value: u32) {
+    let mut weight = 0;
+    table.retain(|key, key_weight| {
+        if key.parse::<u32>().is_ok() {
+            weight += *key_weight;
+            return false;
+        }
+        true
+    });
+    table.insert(value.to_string(), weight);
+}
+
+// Daily and monthly AP are capped at 15000 each, and --scale-by-daily-monthly-ap doubles the fixed value at both caps
+const FULL_SCALE_POINTS: u32 = 30000;
+
+// Grows the fixed value by its share of FULL_SCALE_POINTS, so value * (1 + min(points, FULL_SCALE_POINTS) / FULL_SCALE_POINTS)
+fn scale_fixed_value(table: &mut BTreeMap<String, u32>, points: u32) {
+    let Some(value) = table.keys().find_map(|key| key.parse::<u32>().ok()) else {
+        return;
+    };
+    let scaled = value as u64 * (FULL_SCALE_POINTS + points.min(FULL_SCALE_POINTS)) as u64 / FULL_SCALE_POINTS as u64;
+    replace_fixed_value(table, scaled as u32);
+}
+
+// Drops zero weights, unless that would empty the table and change how Archipelago defaults it
+fn prune_zero_weights(name: &str, table: &mut BTreeMap<String, u32>) {
+    if table.values().all(|weight| *weight == 0) {
+        if !table.is_empty() {
+            warn!("Every weight in {} is 0, leaving it unpruned", name);
+        }
+        return;
+    }
+    table.retain(|_, weight| *weight != 0);
+}
+
+impl OutputOptions {
+    fn prune_zero_weights(&mut self) {
+        for name in WEIGHT_TABLES {
+            if let Some(table) = self.weight_table_mut(name) {
+                prune_zero_weights(name, table);
+            }
+        }
+        for trigger in &mut self.triggers {
+            for (option_name, value) in trigger.options.values_mut().flatten() {
+                if let OptionValue::Table(table) = value {
+                    prune_zero_weights(option_name, table);
+                }
+            }
+        }
+    }
+
+    fn weight_table_mut(&mut self, name: &str) -> Option<&mut BTreeMap<String, u32>> {
+        match name {
+            "progression_balancing" => Some(&mut self.progression_balancing),
+            "accessibility" => Some(&mut self.accessibility),
+            "character" => Some(&mut self.character),
+            "character_profession" => Some(&mut self.character_profession),
+            "character_race" => Some(&mut self.character_race),
+            "starting_mainhand_weapon" => Some(&mut self.starting_mainhand_weapon),
+            "starting_offhand_weapon" => Some(&mut self.starting_offhand_weapon),
+            "group_content" => Some(&mut self.group_content),
+            "include_competitive" => Some(&mut self.include_competitive),
+            "achievement_weight" => Some(&mut self.achievement_weight),
+            "quest_weight" => Some(&mut self.quest_weight),
+            "training_weight" => Some(&mut self.training_weight),
+            "world_boss_weight" => Some(&mut self.world_boss_weight),
+            "unique_item_weight" => Some(&mut self.unique_item_weight),
+            "poi_weight" => Some(&mut self.poi_weight),
+            "storyline" => Some(&mut self.storyline),
+            "heal_skill" => Some(&mut self.heal_skill),
+            "gear_slots" => Some(&mut self.gear_slots),
+            "storyline_items" => Some(&mut self.storyline_items),
+            _ => None,
+        }
+    }
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        let mut val = Self::new();
+
+        val.progression_balancing.insert("random".to_string(), 0);
+        val.progression_balancing.insert("random-low".to_string(), 0);
+        val.progression_balancing.insert("random-high".to_string(), 0);
+        val.progression_balancing.insert("disabled".to_string(), 0);
+        val.progression_balancing.insert("normal".to_string(), 50);
+        val.progression_balancing.insert("extreme".to_string(), 0);
+
+        val.accessibility.insert("locations".to_string(), 0);
+        val.accessibility.insert("items".to_string(), 50);
+        val.accessibility.insert("minimal".to_string(), 0);
+
+        val.starting_mainhand_weapon.insert("none".to_string(), 0);
+        val.starting_mainhand_weapon.insert("axe".to_string(), 0);
+        val.starting_mainhand_weapon.insert("dagger".to_string(), 0);
+        val.starting_mainhand_weapon.insert("mace".to_string(), 0);
+        val.starting_mainhand_weapon.insert("pistol".to_string(), 0);
+        val.starting_mainhand_weapon.insert("sword".to_string(), 0);
+        val.starting_mainhand_weapon.insert("scepter".to_string(), 0);
+        val.starting_mainhand_weapon.insert("greatsword".to_string(), 0);
+        val.starting_mainhand_weapon.insert("hammer".to_string(), 0);
+        val.starting_mainhand_weapon.insert("longbow".to_string(), 0);
+        val.starting_mainhand_weapon.insert("rifle".to_string(), 0);
+        val.starting_mainhand_weapon.insert("short_bow".to_string(), 0);
+        val.starting_mainhand_weapon.insert("staff".to_string(), 0);
+        val.starting_mainhand_weapon.insert("random_proficient".to_string(), 50);
+        val.starting_mainhand_weapon.insert("random_proficient_one_handed".to_string(), 0);
+        val.starting_mainhand_weapon.insert("random_proficient_two_handed".to_string(), 0);
+
+        val.starting_offhand_weapon.insert("none".to_string(), 0);
+        val.starting_offhand_weapon.insert("scepter".to_string(), 0);
+        val.starting_offhand_weapon.insert("focus".to_string(), 0);
+        val.starting_offhand_weapon.insert("shield".to_string(), 0);
+        val.starting_offhand_weapon.insert("torch".to_string(), 0);
+        val.starting_offhand_weapon.insert("warhorn".to_string(), 0);
+        val.starting_offhand_weapon.insert("random_proficient".to_string(), 50);
+
+        val.group_content.insert("none".to_string(), 50);
+        val.group_content.insert("five_man".to_string(), 25);
+        val.group_content.insert("ten_man".to_string(), 10);
+
+        val.include_competitive.insert("false".to_string(), 50);
+        val.include_competitive.insert("true".to_string(), 10);
+
+        val.achievement_weight.insert("500".to_string(), 50);
+        val.achievement_weight.insert("random".to_string(), 0);
+        val.achievement_weight.insert("random-low".to_string(), 0);
+        val.achievement_weight.insert("random-high".to_string(), 0);
+
+        val.quest_weight.insert("100".to_string(), 50);
+        val.quest_weight.insert("random".to_string(), 0);
+        val.quest_weight.insert("random-low".to_string(), 0);
+        val.quest_weight.insert("random-high".to_string(), 0);
+
+        val.training_weight.insert("100".to_string(), 50);
+        val.training_weight.insert("random".to_string(), 0);
+        val.training_weight.insert("random-low".to_string(), 0);
+        val.training_weight.insert("random-high".to_string(), 0);
+
+        val.world_boss_weight.insert("250".to_string(), 50);
+        val.world_boss_weight.insert("random".to_string(), 0);
+        val.world_boss_weight.insert("random-low".to_string(), 0);
+        val.world_boss_weight.insert("random-high".to_string(), 0);
+
+        val.unique_item_weight.insert("250".to_string(), 50);
+        val.unique_item_weight.insert("random".to_string(), 0);
+        val.unique_item_weight.insert("random-low".to_string(), 0);
+        val.unique_item_weight.insert("random-high".to_string(), 0);
+
+        val.poi_weight.insert("250".to_string(), 50);
+        val.poi_weight.insert("random".to_string(), 0);
+        val.poi_weight.insert("random-low".to_string(), 0);
+        val.poi_weight.insert("random-high".to_string(), 0);
+
+        val.heal_skill.insert("randomize".to_string(), 1);
+        val.heal_skill.insert("early".to_string(), 10);
+        val.heal_skill.insert("starting".to_string(), 50);
+
+        val.gear_slots.insert("randomize".to_string(), 5);
+        val.gear_slots.insert("early".to_string(), 50);
+        val.gear_slots.insert("starting".to_string(), 10);
+
+        val.storyline_items.insert("all".to_string(), 0);
+        val.storyline_items.insert("core".to_string(), 0);
+        val.storyline_items.insert("storyline".to_string(), 0);
+        val.storyline_items.insert("storyline_plus".to_string(), 50);
+
+        val
+    }
+}
+
+const REQUIRED_PERMISSIONS: [&str; 2] = ["characters", "progression"];
+
+#[derive(Deserialize, Debug, Default)]
+struct TokenInfo {
+    #[serde(default)]
+    name: String,
+    permissions: HashSet<String>,
+}
+
+// Both are only filled in for keys with progression
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Account {
+    #[serde(default)]
+    daily_ap: u32,
+    #[serde(default)]
+    monthly_ap: u32,
+}
+
+impl Account {
+    // Not the account's achievement points, which need every achievement's tiers counted up
+    fn daily_monthly_ap(&self) -> u32 {
+        self.daily_ap + self.monthly_ap
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct BuildInfo {
+    id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Character {
+    name: String,
+    race: String,
+    profession: String,
+}
+
+#[derive(EnumIter, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Storyline {
+    Core,
+    Season1,
+    Season2,
+    HeartOfThorns,
+    Season3,
+    PathOfFire,
+    Season4,
+    IcebroodSaga,
+    EndOfDragons,
+    SecretsOfTheObscure,
+    JanthirWilds,
+}
+
+impl Storyline {
+    const fn id(&self) -> &'static str {
+        match self {
+            Storyline::Core => "215AAA0F-CDAC-4F93-86DA-C155A99B5784",
+            Storyline::Season1 => "A49D0CD7-E725-4141-8E10-180F1CED7CAF",
+            Storyline::Season2 => "A515A1D3-4BD7-4594-AE30-2C5D05FF5960",
+            Storyline::HeartOfThorns => "B8901E58-DC9D-4525-ADB2-79C93593291E",
+            Storyline::Season3 => "09766A86-D88D-4DF2-9385-259E9A8CA583",
+            Storyline::PathOfFire => "EAB597C0-C484-4FD3-9430-31433BAC81B6",
+            Storyline::Season4 => "C22AFD21-667A-4AA8-8210-AC74EAEE58BB",
+            Storyline::IcebroodSaga => "EDCAE800-302A-4D9B-8331-3CC769ADA0B3",
+            Storyline::EndOfDragons => "D1B709AB-92B6-4EE9-8B40-2B7C628E5022",
+            Storyline::SecretsOfTheObscure => "AEE99452-D323-4ABB-8F49-D7C0A752CBD1",
+            // Not yet confirmed against the live /v2/stories/seasons list, fetch_seasons falls back to fallback_name
+            Storyline::JanthirWilds => "B4D1E1D4-4C91-4A2B-9D4E-2F3C8E5A7B10",
+        }
+    }
+
+    // The season's name in the API, for storylines whose id might be wrong
+    const fn fallback_name(&self) -> Option<&'static str> {
+        match self {
+            Storyline::JanthirWilds => Some("Janthir Wilds"),
+            _ => None,
+        }
+    }
+
+    const fn snake_case(&self) -> &'static str {
+        match self {
+            Storyline::Core => "core",
+            Storyline::Season1 => "season_1",
+            Storyline::Season2 => "season_2",
+            Storyline::HeartOfThorns => "heart_of_thorns",
+            Storyline::Season3 => "season_3",
+            Storyline::PathOfFire => "path_of_fire",
+            Storyline::Season4 => "season_4",
+            Storyline::IcebroodSaga => "icebrood_saga",
+            Storyline::EndOfDragons => "end_of_dragons",
+            Storyline::SecretsOfTheObscure => "secrets_of_the_obscure",
+            Storyline::JanthirWilds => "janthir_wilds",
+        }
+    }
+
+    const fn default_weight(&self) -> u32 {
+        match self {
+            Storyline::Core => 1,
+            Storyline::Season1 => 2,
+            Storyline::Season2 => 4,
+            Storyline::HeartOfThorns => 8,
+            Storyline::Season3 => 16,
+            Storyline::PathOfFire => 32,
+            Storyline::Season4 => 64,
+            Storyline::IcebroodSaga => 128,
+            Storyline::EndOfDragons => 256,
+            Storyline::SecretsOfTheObscure => 512,
+            Storyline::JanthirWilds => 1024,
+        }
+    }
+
+    const fn max_quests(&self) -> usize {
+        match self {
+            Storyline::Core => 49,
+            Storyline::Season1 => 30,
+            Storyline::Season2 => 32,
+            Storyline::HeartOfThorns => 16,
+            Storyline::Season3 => 36,
+            Storyline::PathOfFire => 16,
+            Storyline::Season4 => 30,
+            Storyline::IcebroodSaga => 41,
+            Storyline::EndOfDragons => 27,
+            Storyline::SecretsOfTheObscure => 20,
+            // Unconfirmed like its id, counted from the API instead when all quests are fetched
+            Storyline::JanthirWilds => 18,
+        }
+    }
+
+    #[allow(dead_code)]
+    const fn max_training(&self, is_revenant: bool) -> usize {
+        match self {
+            Storyline::Core => {if is_revenant {82} else {86}},
+            Storyline::Season1 => 0,
+            Storyline::Season2 => 0,
+            Storyline::HeartOfThorns => {if is_revenant {18} else {19}},
+            Storyline::Season3 => 0,
+            Storyline::PathOfFire => {if is_revenant {18} else {19}},
+            Storyline::Season4 => 0,
+            Storyline::IcebroodSaga => 0,
+            Storyline::EndOfDragons => {if is_revenant {18} else {19}},
+            Storyline::SecretsOfTheObscure => 0,
+            Storyline::JanthirWilds => 0,
+        }
+    }
+}
+
+// Names that set several storylines at once in a character's storyline map.
+// Groups apply in this order, so a later group wins where they overlap, and storylines named on their own win over both
+const STORYLINE_GROUPS: [(&str, &[Storyline]); 3] = [
+    ("living_world", &[Storyline::Season1, Storyline::Season2, Storyline::Season3, Storyline::Season4,
+                       Storyline::IcebroodSaga]),
+    ("expansions", &[Storyline::HeartOfThorns, Storyline::PathOfFire, Storyline::EndOfDragons,
+                     Storyline::SecretsOfTheObscure, Storyline::JanthirWilds]),
+    ("post_pof", &[Storyline::Season4, Storyline::IcebroodSaga, Storyline::EndOfDragons,
+                   Storyline::SecretsOfTheObscure, Storyline::JanthirWilds]),
+];
+
+fn expand_storyline_groups(weights: &HashMap<String, u32>) -> HashMap<String, u32> {
+    let mut expanded = HashMap::new();
+    for (group, storylines) in STORYLINE_GROUPS {
+        if let Some(&weight) = weights.get(group) {
+            for storyline in storylines {
+                expanded.insert(storyline.snake_case().to_string(), weight);
+            }
+        }
+    }
+    for (name, &weight) in weights {
+        if !STORYLINE_GROUPS.iter().any(|(group, _)| group == name) {
+            expanded.insert(name.clone(), weight);
+        }
+    }
+    expanded
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Season {
+    id: String,
+    // Missing from caches written before it was read
+    #[serde(default)]
+    name: String,
+    #[serde(rename = "stories")]
+    story_ids: HashSet<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Quest {
+    id: u32,
+    name: String,
+    #[serde(rename = "story")]
+    story_id: u32,
+}
+
+// Returns the cached value if the file exists and is younger than the ttl
+fn load_cache<T>(path: &Path, ttl: Duration) -> Option<T> where T: DeserializeOwned {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    if modified.elapsed().ok()? > ttl {
+        return None;
+    }
+    let file = File::open(path).ok()?;
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+// A cache that can't be written just means a slower next run, so only warn
+fn store_cache<T>(path: &Path, value: &T) where T: Serialize {
+    let result = path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| File::create(path))
+        .and_then(|file| serde_json::to_writer(file, value).map_err(std::io::Error::from));
+    if let Err(err) = result {
+        warn!("Could not write cache {}: {}", path.display(), err);
+    }
+}
+
+// Fetches the season behind every storyline, returning the storylines whose season the source doesn't have
+async fn fetch_seasons(data: &Arc<dyn Gw2Source>, progress: ProgressBar)
+    -> Result<(HashMap<String, Season>, Vec<Storyline>), GenError> {
+    let mut tasks = FuturesUnordered::new();
+    for storyline in Storyline::iter() {
+        let data = Arc::clone(data);
+        tasks.push(tokio::spawn(async move { (storyline, data.season(storyline.id()).await) }));
+    }
+
+    progress.set_length(tasks.len() as u64);
+    let mut seasons = HashMap::<String, Season>::new();
+    while let Some(finished_task) = tasks.next().await {
+        let (storyline, season) = finished_task?;
+        match season {
+            Ok(season) => {
+                seasons.insert(season.id.clone(), season);
+            }
+            Err(err) if err.is_not_found() => debug!("{} season lookup failed: {}", storyline.snake_case(), err),
+            Err(err) => return Err(err),
+        }
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    let unfound: Vec<Storyline> = Storyline::iter()
+        .filter(|storyline| !seasons.contains_key(storyline.id()) && storyline.fallback_name().is_some())
+        .collect();
+    if !unfound.is_empty() {
+        find_seasons_by_name(data, &unfound, &mut seasons).await?;
+    }
+
+    // The API could also answer with a different season than was asked for
+    let missing = Storyline::iter().filter(|storyline| !seasons.contains_key(storyline.id())).collect();
+    Ok((seasons, missing))
+}
+
+// Looks through the season list for storylines whose id wasn't found, filing any match under the id
+// the rest of the generator looks it up by
+async fn find_seasons_by_name(data: &Arc<dyn Gw2Source>, storylines: &[Storyline],
+                              seasons: &mut HashMap<String, Season>) -> Result<(), GenError> {
+    let ids = match data.season_ids().await {
+        Ok(ids) => ids,
+        Err(err) if err.is_not_found() => {
+            debug!("No season list to search: {}", err);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+    for id in ids {
+        if Storyline::iter().any(|storyline| storyline.id() == id) {
+            continue;
+        }
+        let season = data.season(&id).await?;
+        let Some(storyline) = storylines.iter()
+            .find(|storyline| storyline.fallback_name().is_some_and(|name| name.eq_ignore_ascii_case(&season.name))) else {
+            continue;
+        };
+        warn!("{} isn't at {}, using {} ({}) found by name; please report this", storyline.snake_case(),
+              storyline.id(), season.name, season.id);
+        seasons.insert(storyline.id().to_string(), season);
+    }
+    Ok(())
+}
+
+// Whether a season is one a Storyline already covers, by id or by fallback name
+fn is_known_season(season: &Season) -> bool {
+    Storyline::iter().any(|storyline| {
+        storyline.id() == season.id
+            || storyline.fallback_name().is_some_and(|name| name.eq_ignore_ascii_case(&season.name))
+    })
+}
+
+// Seasons the API lists that no Storyline covers, e.g. content released after this version
+async fn discover_seasons(data: &Arc<dyn Gw2Source>) -> Result<Vec<Season>, GenError> {
+    let mut discovered = Vec::new();
+    for id in data.season_ids().await? {
+        if Storyline::iter().any(|storyline| storyline.id() == id) {
+            continue;
+        }
+        let season = data.season(&id).await?;
+        if is_known_season(&season) {
+            continue;
+        }
+        warn!("Auto-discovered season {} ({}), rolling it as {} with default settings",
+              season.name, season.id, future_storyline_name(&season));
+        discovered.push(season);
+    }
+    Ok(discovered)
+}
+
+// snake_case of the season name, like the Storyline names
+fn future_storyline_name(season: &Season) -> String {
+    let name = if season.name.is_empty() { &season.id } else { &season.name };
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+// Fetches every character's completions at once, the client's in-flight limit keeping them in check.
+// A character that fails doesn't fail the others, only a panicked task ends the whole fetch
+async fn fetch_all_completed<I>(data: &Arc<dyn Gw2Source>, names: I, progress: &ProgressBar)
+    -> Result<HashMap<String, Result<HashSet<u32>, GenError>>, GenError> where I: IntoIterator<Item = String> {
+    let mut tasks = FuturesUnordered::new();
+    for name in names {
+        let data = Arc::clone(data);
+        tasks.push(tokio::spawn(async move {
+            let completed = data.completed_quests(&name).await;
+            (name, completed)
+        }));
+    }
+
+    progress.set_length(tasks.len() as u64);
+    let mut completed_quests = HashMap::new();
+    while let Some(finished_task) = tasks.next().await {
+        let (name, completed) = finished_task?;
+        completed_quests.insert(name, completed);
+        progress.inc(1);
+    }
+    Ok(completed_quests)
+}
+
+fn missing_season_names(missing: &[Storyline]) -> Vec<String> {
+    missing.iter().map(|storyline| format!("{} ({})", storyline.snake_case(), storyline.id())).collect()
+}
+
+// indicatif already hides bars when stderr isn't a terminal
+fn progress_bar(show: bool, message: &'static str, len: u64) -> ProgressBar {
+    if !show {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template("{msg:>12} [{bar:30}] {pos}/{len}")
+        .expect("progress template is valid")
+        .progress_chars("=> ");
+    ProgressBar::new(len).with_style(style).with_message(message)
+}
+
+// The API-derived parts of an existing output that --merge replaces
+const MERGED_OPTIONS: [&str; 3] = ["character", "triggers", "storyline"];
+
+// Reads the output already at path and swaps in the freshly generated MERGED_OPTIONS, keeping everything else
+fn merge_output(path: &Path, output: &Output) -> Result<serde_yaml::Value, GenError> {
+    let file = File::open(path).map_err(|err| GenError::File(path.to_path_buf(), err))?;
+    let mut existing: serde_yaml::Value = serde_yaml::from_reader(BufReader::new(file))
+        .map_err(|err| GenError::InputParse(path.to_path_buf(), err.into()))?;
+    let generated = serde_yaml::to_value(output)?;
+
+    let Some(existing_options) = existing.get_mut(GAME_NAME).and_then(|options| options.as_mapping_mut()) else {
+        return Err(GenError::Config(format!("{} has no {} options to merge into", path.display(), GAME_NAME)));
+    };
+    for name in MERGED_OPTIONS {
+        if let Some(value) = generated.get(GAME_NAME).and_then(|options| options.get(name)) {
+            existing_options.insert(serde_yaml::Value::String(name.to_string()), value.clone());
+        }
+    }
+    Ok(existing)
+}
+
+#[derive(Serialize)]
+struct TriggerFragment<'a> {
+    triggers: Vec<&'a Trigger>,
+}
+
+// The character trigger plus the storyline triggers named in its storyline table
+fn character_triggers<'a>(triggers: &'a [Trigger], character_name: &str) -> Vec<&'a Trigger> {
+    let Some(character_trigger) = triggers.iter()
+        .find(|trigger| trigger.option_name == "character" && trigger.option_result == character_name) else {
+        return Vec::new();
+    };
+    let storylines = match character_trigger.options.get(GAME_NAME).and_then(|options| options.get("storyline")) {
+        Some(OptionValue::Table(storylines)) => Some(storylines),
+        _ => None,
+    };
+    let mut character_triggers = vec![character_trigger];
+    character_triggers.extend(triggers.iter().filter(|trigger| {
+        trigger.option_name == "storyline" && storylines.is_some_and(|storylines| storylines.contains_key(&trigger.option_result))
+    }));
+    character_triggers
+}
+
+// Writes base.yaml with every option except the triggers, and <character>.yaml with each character's triggers.
+// These are fragments: append the triggers of every character file to the base file's triggers before use
+fn write_split(dir: &Path, output: &Output, style: Option<YamlStyle>, stamp: Option<&str>) -> Result<(), GenError> {
+    fs::create_dir_all(dir).map_err(|err| GenError::File(dir.to_path_buf(), err))?;
+
+    let mut base = serde_yaml::to_value(output)?;
+    if let Some(options) = base.get_mut(GAME_NAME).and_then(|options| options.as_mapping_mut()) {
+        options.insert(serde_yaml::Value::String("triggers".to_string()), serde_yaml::Value::Sequence(Vec::new()));
+    }
+    let base_path = dir.join("base.yaml");
+    write_atomically(&base_path, |mut file| {
+        write_stamp(&mut file, stamp, &base_path)?;
+        writeln!(file, "# Fragment: the triggers from the character files in this directory go under {} triggers", GAME_NAME)
+            .map_err(|err| GenError::File(base_path.clone(), err))?;
+        write_output(file, &base, Format::Yaml, None, style)
+    })?;
+
+    for character_name in output.game_options.character.keys() {
+        let fragment = TriggerFragment {
+            triggers: character_triggers(&output.game_options.triggers, character_name),
+        };
+        let path = dir.join(format!("{}.yaml", character_name));
+        write_atomically(&path, |mut file| {
+            write_stamp(&mut file, stamp, &path)?;
+            writeln!(file, "# Fragment: add these triggers to the {} triggers in base.yaml", GAME_NAME)
+                .map_err(|err| GenError::File(path.clone(), err))?;
+            write_output(file, &fragment, Format::Yaml, None, style)
+        })?;
+    }
+    info!("Wrote {} character fragments to {}", output.game_options.character.len(), dir.display());
+    Ok(())
+}
+
+// Copies path to a timestamped backup next to it, then removes all but the newest keep backups
+fn backup_output(path: &Path, keep: usize) -> Result<PathBuf, GenError> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    // Milliseconds, stepped past any backup already there so two quick runs can't overwrite one
+    let mut timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let (backup, mut backup_file) = loop {
+        let backup = path.with_file_name(format!("{}.{}.bak", file_name, timestamp));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&backup) {
+            Ok(file) => break (backup, file),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => timestamp += 1,
+            Err(err) => return Err(GenError::File(backup, err)),
+        }
+    };
+    let mut original = File::open(path).map_err(|err| GenError::Read(path.to_path_buf(), err))?;
+    std::io::copy(&mut original, &mut backup_file).map_err(|err| GenError::File(backup.clone(), err))?;
+
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let entries = fs::read_dir(dir).map_err(|err| GenError::File(dir.to_path_buf(), err))?;
+    let mut backups: Vec<(u128, PathBuf)> = entries.filter_map(|entry| {
+        let entry = entry.ok()?;
+        let name = entry.file_name().into_string().ok()?;
+        let timestamp = name.strip_prefix(&file_name)?.strip_prefix('.')?.strip_suffix(".bak")?.parse().ok()?;
+        Some((timestamp, entry.path()))
+    }).collect();
+    backups.sort();
+    let excess = backups.len().saturating_sub(keep.max(1));
+    for (_, old) in backups.into_iter().take(excess) {
+        fs::remove_file(&old).map_err(|err| GenError::File(old.clone(), err))?;
+    }
+    Ok(backup)
+}
+
+// Reads path back as an Output and names the top level keys and game options that came back different
+fn verify_roundtrip(path: &Path, output: &Output, format: Format) -> Result<(), GenError> {
+    let file = File::open(path).map_err(|err| GenError::File(path.to_path_buf(), err))?;
+    let reader = BufReader::new(file);
+    let reread: Output = match format {
+        Format::Yaml => serde_yaml::from_reader(reader).map_err(|err| GenError::InputParse(path.to_path_buf(), err.into()))?,
+        Format::Json => serde_json::from_reader(reader).map_err(|err| GenError::InputParse(path.to_path_buf(), err.into()))?,
+    };
+    if reread == *output {
+        return Ok(());
+    }
+
+    let (expected, found) = (serde_yaml::to_value(output)?, serde_yaml::to_value(&reread)?);
+    let mut differences = Vec::new();
+    for key in ["name", "description", "game"] {
+        if expected.get(key) != found.get(key) {
+            differences.push(key.to_string());
+        }
+    }
+    if let Some(options) = expected.get(GAME_NAME).and_then(|options| options.as_mapping()) {
+        for (name, value) in options {
+            if found.get(GAME_NAME).and_then(|options| options.get(name)) != Some(value) {
+                differences.push(name.as_str().unwrap_or_default().to_string());
+            }
+        }
+    }
+    Err(GenError::RoundTrip(path.to_path_buf(), differences))
+}
+
+// Writes next to path and renames over it, so a failed or interrupted write never leaves a truncated file
+fn write_atomically<F>(path: &Path, write: F) -> Result<(), GenError> where F: FnOnce(File) -> Result<(), GenError> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let file = File::create(&temp_path).map_err(|err| GenError::File(temp_path.clone(), err))?;
+    if let Err(err) = write(file) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    fs::rename(&temp_path, path).map_err(|err| GenError::File(path.to_path_buf(), err))
+}
+
+// serde_yaml can't write comments, so the --stamp header goes in ahead of the document
+fn write_stamp<W>(writer: &mut W, stamp: Option<&str>, path: &Path) -> Result<(), GenError> where W: std::io::Write {
+    match stamp {
+        Some(stamp) => writer.write_all(stamp.as_bytes()).map_err(|err| GenError::File(path.to_path_buf(), err)),
+        None => Ok(()),
+    }
+}
+
+// annotations adds storyline completion comments, which only yaml can carry
+fn write_output<W, T>(mut writer: W, output: &T, format: Format, annotations: Option<&Summary>,
+                      style: Option<YamlStyle>) -> Result<(), GenError>
+    where W: std::io::Write, T: Serialize {
+    match format {
+        Format::Yaml => {
+            let mut yaml = serde_yaml::to_string(output)?;
+            if let Some(summary) = annotations {
+                yaml = annotate_yaml(&yaml, summary);
+            }
+            if let Some(style) = style {
+                yaml = restyle_yaml(&yaml, style);
+            }
+            // Both serializer errors read "failed to serialize output", serde_yaml just has no io constructor
+            writer.write_all(yaml.as_bytes()).map_err(serde_json::Error::io)?;
+        }
+        Format::Json => serde_json::to_writer_pretty(writer, output)?,
+    }
+    Ok(())
+}
+
+const COMPACT_WIDTH: usize = 100;
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+// Works on serde_yaml's own layout: two space indents, sequences level with their key, plain one line scalars
+fn restyle_yaml(yaml: &str, style: YamlStyle) -> String {
+    let lines: Vec<&str> = yaml.lines().collect();
+    let mut restyled = String::with_capacity(yaml.len());
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        let indent = indent_of(line);
+        match style {
+            YamlStyle::Expanded => {
+                // Every option and trigger sits two deep, below the game's own key
+                if indent == 2 && index > 0 && indent_of(lines[index - 1]) >= 2 && !lines[index - 1].ends_with(':') {
+                    restyled.push('\n');
+                }
+            }
+            YamlStyle::Compact => {
+                // A key whose children are all scalars goes on one line as a flow mapping
+                let trimmed = line.trim_start();
+                if trimmed.ends_with(':') && !trimmed.starts_with("- ") && !trimmed.contains(" #") {
+                    let children: Vec<&str> = lines[index + 1..].iter()
+                        .take_while(|child| indent_of(child) > indent)
+                        .copied()
+                        .collect();
+                    let scalars = !children.is_empty() && children.iter().all(|child| {
+                        let entry = child.trim_start();
+                        indent_of(child) == indent + 2
+                            && !entry.starts_with("- ") && !entry.ends_with(':') && !entry.contains(" #")
+                    });
+                    if scalars {
+                        let entries: Vec<&str> = children.iter().map(|child| child.trim_start()).collect();
+                        let compact = format!("{} {{{}}}", line, entries.join(", "));
+                        if compact.len() <= COMPACT_WIDTH {
+                            restyled.push_str(&compact);
+                            restyled.push('\n');
+                            index += 1 + children.len();
+                            continue;
+                        }
+                    }
+                }
+            }
+        }
+        restyled.push_str(line);
+        restyled.push('\n');
+        index += 1;
+    }
+    restyled
+}
+
+// serde_yaml can't emit comments, so they're appended to each storyline trigger's option_result line afterwards
+fn annotate_yaml(yaml: &str, summary: &Summary) -> String {
+    let mut comments = HashMap::new();
+    for character in &summary.characters {
+        for progress in &character.storylines {
+            comments.insert(format!("{} {}", progress.storyline.snake_case(), character.name),
+                            format!("{} of {} quests completed", progress.completed, progress.total));
+        }
+    }
+
+    let mut annotated = String::with_capacity(yaml.len());
+    for line in yaml.lines() {
+        annotated.push_str(line);
+        let result = line.trim_start().trim_start_matches("- ").strip_prefix("option_result: ")
+            .and_then(|value| serde_yaml::from_str::<String>(value).ok());
+        if let Some(comment) = result.and_then(|result| comments.get(&result)) {
+            annotated.push_str(" # ");
+            annotated.push_str(comment);
+        }
+        annotated.push('\n');
+    }
+    annotated
+}
+
+#[derive(Clone)]
+struct StorylineProgress {
+    storyline: Storyline,
+    completed: usize,
+    total: usize,
+}
+
+impl StorylineProgress {
+    fn remaining(&self) -> usize {
+        self.total.saturating_sub(self.completed)
+    }
+}
+
+#[derive(Clone)]
+struct CharacterSummary {
+    name: String,
+    // Set when generating across several accounts
+    account: Option<String>,
+    // Not found on the account and not marked new
+    missing: bool,
+    storylines: Vec<StorylineProgress>,
+}
+
+// What build_output saw for each character, for the report printed after a run
+#[derive(Default)]
+struct Summary {
+    characters: Vec<CharacterSummary>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Processed {} characters", self.characters.len())?;
+        for character in &self.characters {
+            match &character.account {
+                Some(account) => writeln!(f, "{} ({})", character.name, account)?,
+                None => writeln!(f, "{}", character.name)?,
+            }
+            if character.storylines.is_empty() {
+                continue;
+            }
+            writeln!(f, "  {:<24} {:>9} {:>9}", "storyline", "completed", "remaining")?;
+            for progress in &character.storylines {
+                writeln!(f, "  {:<24} {:>9} {:>9}",
+                         progress.storyline.snake_case(),
+                         format!("{}/{}", progress.completed, progress.total),
+                         progress.remaining())?;
+            }
+        }
+
+        let missing: Vec<_> = self.characters.iter()
+            .filter(|character| character.missing)
+            .map(|character| character.name.as_str())
+            .collect();
+        if !missing.is_empty() {
+            writeln!(f, "Randomized because they weren't found on the account: {}", missing.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn print_storylines() {
+    println!("{:<24} {:>7} {:>7}  id", "storyline", "weight", "quests");
+    for storyline in Storyline::iter() {
+        println!("{:<24} {:>7} {:>7}  {}",
+                 storyline.snake_case(), storyline.default_weight(), storyline.max_quests(), storyline.id());
+    }
+    println!();
+    println!("{:<24} storylines", "group");
+    for (group, storylines) in STORYLINE_GROUPS {
+        let names: Vec<_> = storylines.iter().map(|storyline| storyline.snake_case()).collect();
+        println!("{:<24} {}", group, names.join(", "));
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+impl Summary {
+    fn write_csv<W>(&self, mut writer: W) -> std::io::Result<()> where W: std::io::Write {
+        writeln!(writer, "character,storyline,completed,total,remaining")?;
+        for character in &self.characters {
+            for progress in &character.storylines {
+                writeln!(writer, "{},{},{},{},{}",
+                         csv_field(&character.name),
+                         progress.storyline.snake_case(),
+                         progress.completed,
+                         progress.total,
+                         progress.remaining())?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+// Generator settings that come from the command line rather than the input file
+struct BuildOptions {
+    strict: bool,
+    // Whether quests holds every quest, so storyline sizes can be counted from it
+    count_quests: bool,
+    storyline_mode: StorylineMode,
+    min_remaining: usize,
+    verbose_quests: bool,
+    auto_weight: AutoWeight,
+    character_order: CharacterOrder,
+    // From --include-future-storylines, rolled after the known storylines
+    future_seasons: Vec<Season>,
+}
+
+fn storyline_trigger(storyline_name: &str, character_name: &str, remaining_quests: usize) -> Trigger {
+    let mut trigger = Trigger::new("storyline".to_string(), format!("{} {}", storyline_name, character_name));
+    trigger.with_game_option("max_quests", OptionValue::Number(remaining_quests as u32));
+    // trigger.with_game_option("max_training", OptionValue::Value(format!("{}", storyline.max_training(profession == "Revenant") - completed_count)));
+    trigger.with_game_option("storyline", OptionValue::Value(storyline_name.to_string()));
+    trigger
+}
+
+fn build_output(input: &Input,
+                options: &BuildOptions,
+                characters: &HashMap<String, Character>,
+                seasons: &HashMap<String, Season>,
+                quests: &HashMap<u32, Quest>,
+                completed: &HashMap<String, HashSet<u32>>) -> Result<(Output, Summary), GenError> {
+    // Count the quests the API knows about for each storyline, keeping the hardcoded
+    // count when the season or quest data has nothing for it
+    let max_quests: HashMap<Storyline, usize> = Storyline::iter().map(|storyline| {
+        if !options.count_quests {
+            return (storyline, storyline.max_quests());
+        }
+        let counted = seasons.get(storyline.id()).map_or(0, |season| {
+            quests.values().filter(|quest| season.story_ids.contains(&quest.story_id)).count()
+        });
+        if counted == 0 {
+            return (storyline, storyline.max_quests());
+        }
+        if counted != storyline.max_quests() {
+            info!("{} has {} quests, but the hardcoded count is {}",
+                  storyline.snake_case(), counted, storyline.max_quests());
+        }
+        (storyline, counted)
+    }).collect();
+
+    let mut output = Output::default();
+    if let Some(player_name) = &input.player_name {
+        output.name = player_name.clone();
+    }
+    if let Some(description) = &input.description {
+        output.description = description.clone();
+    }
+    output.game_options.mist_fragments_required = input.mist_fragments_required;
+    output.game_options.extra_mist_fragment_percent = input.extra_mist_fragment_percent;
+    for (option_name, value) in [("achievement_weight", input.achievement_weight), ("quest_weight", input.quest_weight),
+                                 ("training_weight", input.training_weight), ("world_boss_weight", input.world_boss_weight)] {
+        if let (Some(value), Some(table)) = (value, output.game_options.weight_table_mut(option_name)) {
+            replace_fixed_value(table, value);
+        }
+    }
+    for (option_name, weights) in &input.overrides {
+        match output.game_options.weight_table_mut(option_name) {
+            Some(table) => table.extend(weights.iter().map(|(value, weight)| (value.clone(), *weight))),
+            None => warn!("Ignoring override for unknown option {}", option_name),
+        }
+    }
+    let mut summary = Summary::default();
+    // Process characters in a fixed order so the triggers list is stable between runs
+    let mut character_inputs: Vec<_> = input.characters.iter().collect();
+    match options.character_order {
+        CharacterOrder::Alpha => character_inputs.sort_by_key(|(name, _)| *name),
+        CharacterOrder::Input => character_inputs.sort_by_key(|(name, character)| (character.order, *name)),
+    }
+    for (character_name, character_options) in character_inputs {
+        let character = characters.get(character_name);
+        let mut character_summary = CharacterSummary {
+            name: character_name.clone(),
+            account: None,
+            missing: character.is_none() && !character_options.new,
+            storylines: Vec::new(),
+        };
+
+        let weight = character_options.weight;
+        output.game_options.character.insert(character_name.clone(), weight);
+
+        let mut trigger = Trigger::new("character".to_string(), character_name.clone());
+        trigger.with_game_option("character_profession", OptionValue::Table(BTreeMap::new()))
+            .with_game_option("character_race", OptionValue::Table(BTreeMap::new()));
+
+        let completed_quest_ids;
+        let profession;
+        let race;
+        if let Some(character) = character {
+            profession = character.profession.clone();
+            race = character.race.clone();
+            completed_quest_ids = completed.get(character_name);
+        }
+        else {
+            if !character_options.new {
+                if options.strict {
+                    return Err(GenError::CharacterNotFound(character_name.clone()));
+                }
+                warn!("{} was not found on the account, randomizing its profession and race", character_name);
+            }
+            profession = "random".to_string();
+            race = "random".to_string();
+            completed_quest_ids = None;
+        }
+
+        // Completions no tracked season covers, to spot gaps in the season to story mapping
+        if let Some(completed) = completed_quest_ids {
+            let mut orphaned: Vec<_> = completed.iter()
+                .filter(|id| quests.get(id).is_none_or(|quest| {
+                    !seasons.values().any(|season| season.story_ids.contains(&quest.story_id))
+                }))
+                .collect();
+            if !orphaned.is_empty() {
+                orphaned.sort();
+                info!("{} has {} completed quests outside every tracked storyline", character_name, orphaned.len());
+                debug!("{} orphaned quests: {:?}", character_name, orphaned);
+            }
+        }
+
+        let profession_weights = character_options.profession.clone()
+            .unwrap_or_else(|| HashMap::from([(profession, input.profession_weight)]));
+        for (profession, weight) in profession_weights {
+            trigger.game_option_table_mut("character_profession").insert(profession, weight)?;
+        }
+        let race_weights = character_options.race.clone()
+            .unwrap_or_else(|| HashMap::from([(race, input.race_weight)]));
+        for (race, weight) in race_weights {
+            trigger.game_option_table_mut("character_race").insert(race, weight)?;
+        }
+        // Without these the global tables apply when this character is rolled
+        for (option_name, weights) in [("heal_skill", &character_options.heal_skill),
+                                       ("gear_slots", &character_options.gear_slots),
+                                       ("include_competitive", &character_options.include_competitive),
+                                       ("group_content", &character_options.group_content)] {
+            if let Some(weights) = weights {
+                trigger.with_game_option(option_name, OptionValue::Table(weights.clone().into_iter().collect()));
+            }
+        }
+
+        trigger.with_game_option("storyline", OptionValue::Table(BTreeMap::new()));
+
+        let storyline_options = character_options.storyline.as_ref().map(expand_storyline_groups);
+        let mut storyline_triggers = Vec::new();
+        for storyline in Storyline::iter() {
+
+            // None for the default weight, which --auto-weight can replace once completion is known
+            let weight = if let Some (weights) = &storyline_options {
+                if weights.contains_key(storyline.snake_case()) {
+                    // Archipelago still knows about a zero weight option, so 0 leaves the storyline out instead
+                    if weights[storyline.snake_case()] == 0 {
+                        continue;
+                    }
+                    Some(weights[storyline.snake_case()])
+                }
+                else if options.storyline_mode == StorylineMode::Default {
+                    None
+                }
+                else {
+                    continue;
+                }
+            } else {
+                None
+            };
+
+            // Already warned about when the seasons were fetched
+            let Some(season) = seasons.get(storyline.id()) else {
+                continue;
+            };
+            let completed_count =
+                if let Some(completed) = completed_quest_ids {
+                     completed.iter()
+                         .filter(|q| quests.get(q).is_some_and(|quest| season.story_ids.contains(&quest.story_id)))
+                         .count()
+                }
+                else {
+                    0
+                }
+            ;
+            // The API can report more completions than we count quests for when the data drifts
+            let remaining_quests = max_quests[&storyline].saturating_sub(completed_count);
+            debug!("{} {}: {} completed, {} remaining",
+                   character_name, storyline.snake_case(), completed_count, remaining_quests);
+            trace!("{:?}", season);
+            if options.verbose_quests {
+                let mut season_quests: Vec<_> = quests.values()
+                    .filter(|quest| season.story_ids.contains(&quest.story_id))
+                    .collect();
+                season_quests.sort_by_key(|quest| quest.id);
+                for quest in season_quests {
+                    let complete = completed_quest_ids.is_some_and(|completed| completed.contains(&quest.id));
+                    info!("{} {}: {}: {}", character_name, storyline.snake_case(), quest.name,
+                          if complete {"Complete"} else {"Incomplete"});
+                }
+            }
+            character_summary.storylines.push(StorylineProgress {
+                storyline,
+                completed: completed_count,
+                total: max_quests[&storyline],
+            });
+
+            // A finished storyline is left out of the character's storyline table entirely:
+            // rolling it would pick an option_result with no quests behind it
+            if remaining_quests == 0 {
+                info!("{} has completed {}, excluding it", character_name, storyline.snake_case());
+                continue;
+            }
+            if remaining_quests < options.min_remaining {
+                info!("{} has only {} {} quests left, excluding it",
+                      character_name, remaining_quests, storyline.snake_case());
+                continue;
+            }
+
+            let weight = weight.unwrap_or_else(|| {
+                options.auto_weight.weight(storyline.default_weight(), remaining_quests, max_quests[&storyline])
+            });
+            let storyline_trigger = storyline_trigger(storyline.snake_case(), character_name, remaining_quests);
+            trigger.game_option_table_mut("storyline").insert(storyline_trigger.option_result.clone(), weight)?;
+            storyline_triggers.push(storyline_trigger);
+        }
+
+        for season in &options.future_seasons {
+            let storyline_name = future_storyline_name(season);
+            let total = quests.values().filter(|quest| season.story_ids.contains(&quest.story_id)).count();
+            let completed_count = completed_quest_ids.map_or(0, |completed| {
+                completed.iter()
+                    .filter(|q| quests.get(q).is_some_and(|quest| season.story_ids.contains(&quest.story_id)))
+                    .count()
+            });
+            let remaining_quests = total.saturating_sub(completed_count);
+            if remaining_quests == 0 || remaining_quests < options.min_remaining {
+                info!("{} has {} of {} {} quests left, excluding it",
+                      character_name, remaining_quests, total, storyline_name);
+                continue;
+            }
+
+            let storyline_trigger = storyline_trigger(&storyline_name, character_name, remaining_quests);
+            let weight = options.auto_weight.weight(default_weight(), remaining_quests, total);
+            trigger.game_option_table_mut("storyline").insert(storyline_trigger.option_result.clone(), weight)?;
+            storyline_triggers.push(storyline_trigger);
+        }
+
+        if storyline_triggers.is_empty() {
+            warn!("{} has no storylines left to roll", character_name);
+        }
+
+        output.game_options.triggers.push(trigger);
+        output.game_options.triggers.extend(storyline_triggers);
+        summary.characters.push(character_summary);
+    }
+
+    Ok((output, summary))
+
+}
+
+struct RateStateGuard {
+    client: Arc<RateLimitedReqwestClient>,
+    path: PathBuf,
+}
+
+impl Drop for RateStateGuard {
+    fn drop(&mut self) {
+        self.client.save_rate_state(&self.path);
+    }
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+// The account's spelling of name, warning when it only matches ignoring case
+fn canonical_name(account_characters: &HashSet<String>, name: String) -> String {
+    if account_characters.contains(&name) {
+        return name;
+    }
+    match account_characters.iter().find(|character| same_name(character, &name)) {
+        Some(character) => {
+            warn!("{} isn't on the account, using {} instead", name, character);
+            character.clone()
+        }
+        None => name,
+    }
+}
+
+fn build_client(args: &Args) -> Result<RateLimitedReqwestClient, GenError> {
+    let mut client = RateLimitedReqwestClient::with_quota(args.rate_limit, Duration::from_millis(args.jitter_ms))?;
+    client.max_retries = args.retries;
+    client.retry_base_delay = Duration::from_millis(args.retry_delay_ms);
+    client.request_timeout = Duration::from_secs(args.timeout_secs);
+    client.in_flight = Semaphore::new(args.max_concurrency.max(1));
+    Ok(client)
+}
+
+async fn check_api(args: &Args) -> Result<(), GenError> {
+    let input = match read_input(args) {
+        Ok(input) => Some(input),
+        Err(err) if err.is_not_found() => None,
+        Err(err) => return Err(err),
+    };
+    let api_keys = find_api_keys(args, input.as_ref());
+    if api_keys.is_empty() {
+        return Err(GenError::MissingApiKey(input_path(args)));
+    }
+    let client = Arc::new(build_client(args)?);
+    let sources: Vec<_> = api_keys.into_iter().map(|api_key| ApiSource {
+        client: Arc::clone(&client),
+        base_url: args.api_base_url.clone(),
+        api_key,
+        lang: args.lang,
+        completed_cache: None,
+    }).collect();
+
+    let build_id = sources[0].build_id().await?.unwrap_or_default();
+    println!("API reachable at {}, game build {}", args.api_base_url, build_id);
+
+    for source in &sources {
+        let token_info = source.token_info().await?.unwrap_or_default();
+        let mut permissions: Vec<_> = token_info.permissions.iter().map(String::as_str).collect();
+        permissions.sort_unstable();
+        println!("Key \"{}\" has permissions: {}", token_info.name, permissions.join(", "));
+
+        let missing: Vec<_> = REQUIRED_PERMISSIONS.into_iter()
+            .filter(|permission| !token_info.permissions.contains(*permission))
+            .collect();
+        if !missing.is_empty() {
+            return Err(GenError::MissingPermissions(missing));
+        }
+    }
+    if sources.len() == 1 {
+        println!("The key is ready to generate");
+    } else {
+        println!("All {} keys are ready to generate", sources.len());
+    }
+    Ok(())
+}
+
+async fn validate_seasons(args: &Args) -> Result<(), GenError> {
+    let data: Arc<dyn Gw2Source> = if let Some(offline_dir) = &args.offline {
+        Arc::new(FixtureSource { dir: resolve_path(offline_dir) })
+    } else {
+        // Seasons are public, so no key is needed
+        Arc::new(ApiSource {
+            client: Arc::new(build_client(args)?),
+            base_url: args.api_base_url.clone(),
+            api_key: String::new(),
+            lang: args.lang,
+            completed_cache: None,
+        })
+    };
+
+    let (seasons, missing) = fetch_seasons(&data, progress_bar(!args.quiet, "seasons", 0)).await?;
+    if !missing.is_empty() {
+        return Err(GenError::MissingSeasons(missing_season_names(&missing)));
+    }
+    info!("All {} storyline seasons were found", seasons.len());
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = Args::parse();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(match args.verbose {
+            _ if args.quiet => Level::WARN,
+            0 => Level::INFO,
+            1 => Level::DEBUG,
+            _ => Level::TRACE,
+        })
+        .init();
+
+    match run(&args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+async fn run(args: &Args) -> Result<(), GenError> {
+    match args.command {
+        Some(Command::Storylines) => {
+            print_storylines();
+            return Ok(());
+        }
+        Some(Command::Validate) => return validate_seasons(args).await,
+        Some(Command::Check) => return check_api(args).await,
+        None => {}
+    }
+
+    let stage = RunStage::default();
+    let deadline = async {
+        match args.timeout_total {
+            Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+            None => std::future::pending().await,
+        }
+    };
+    // Dropping the run cancels whatever requests are still in flight, and nothing is written mid-await
+    tokio::select! {
+        result = generate(args, &stage) => result,
+        Ok(()) = tokio::signal::ctrl_c() => Err(GenError::Interrupted),
+        () = deadline => Err(GenError::TimedOut(args.timeout_total.unwrap_or_default(), stage.get())),
+    }
+}
+
+fn input_path(args: &Args) -> PathBuf {
+    if args.input.as_os_str() == "-" {
+        PathBuf::from("-")
+    } else {
+        resolve_path(&args.input)
+    }
+}
+
+// Yaml unless --input-format or the file extension says otherwise, stdin included
+fn read_input(args: &Args) -> Result<Input, GenError> {
+    let input_path = input_path(args);
+    let reader: Box<dyn std::io::Read> = if input_path.as_os_str() == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        let file = fs::File::open(&input_path).map_err(|err| GenError::Read(input_path.clone(), err))?;
+        Box::new(BufReader::new(file))
+    };
+    let format = args.input_format.or_else(|| Format::from_path(&input_path)).unwrap_or(Format::Yaml);
+    match format {
+        Format::Yaml => serde_yaml::from_reader(reader).map_err(|err| GenError::InputParse(input_path, err.into())),
+        Format::Json => serde_json::from_reader(reader).map_err(|err| GenError::InputParse(input_path, err.into())),
+    }
+}
 
-    const fn max_training(&self, is_revenant: bool) -> usize {
-        match self {
-            Storyline::Core => {if is_revenant {82} else {86}},
-            Storyline::Season1 => 0,
-            Storyline::Season2 => 0,
-            Storyline::HeartOfThorns => {if is_revenant {18} else {19}},
-            Storyline::Season3 => 0,
-            Storyline::PathOfFire => {if is_revenant {18} else {19}},
-            Storyline::Season4 => 0,
-            Storyline::IcebroodSaga => 0,
-            Storyline::EndOfDragons => {if is_revenant {18} else {19}},
-            Storyline::SecretsOfTheObscure => 0,
-        }
+// --api-key wins over the input file, which wins over GW2_API_KEY. Only the input can list several
+fn find_api_keys(args: &Args, input: Option<&Input>) -> Vec<String> {
+    let present = |key: &String| !key.trim().is_empty();
+    if let Some(key) = args.api_key.clone().filter(present) {
+        return vec![key];
     }
+    let input_keys: Vec<String> = input.and_then(|input| input.api_key.as_ref())
+        .map(|keys| keys.keys().into_iter().filter(present).collect())
+        .unwrap_or_default();
+    if !input_keys.is_empty() {
+        return input_keys;
+    }
+    std::env::var("GW2_API_KEY").ok().filter(present).into_iter().collect()
 }
 
-#[derive(Deserialize, Debug)]
-struct Season {
-    id: String,
-    #[serde(rename = "stories")]
-    story_ids: HashSet<u32>,
+// What generate is busy with, for saying where --timeout-total stopped it
+struct RunStage(Mutex<&'static str>);
+
+impl Default for RunStage {
+    fn default() -> Self {
+        RunStage(Mutex::new("reading the input"))
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct Quest {
-    id: u32,
-    name: String,
-    #[serde(rename = "story")]
-    story_id: u32,
+impl RunStage {
+    fn set(&self, stage: &'static str) {
+        *self.0.lock().expect("stage is never poisoned") = stage;
+    }
+
+    fn get(&self) -> &'static str {
+        *self.0.lock().expect("stage is never poisoned")
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    let input: Input = {
-        let file = fs::File::open("input.yaml").unwrap();
-        let reader = BufReader::new(file);
-        serde_yaml::from_reader(reader).unwrap()
+async fn generate(args: &Args, stage: &RunStage) -> Result<(), GenError> {
+    let input_path = input_path(args);
+    let output_path = (args.output.as_os_str() != "-").then(|| resolve_path(&args.output));
+    if args.verify_roundtrip && output_path.is_none() {
+        return Err(GenError::Config("--verify-roundtrip reads the output file back, so it can't write to -".to_string()));
+    }
+
+    let input = read_input(args)?;
+    debug!("{:?}", input);
+
+    let mut player_inputs = input.player_inputs();
+    if !args.only.is_empty() {
+        for (_, player_input) in &mut player_inputs {
+            player_input.characters.retain(|name, _| args.only.iter().any(|only| same_name(only, name)));
+        }
+    }
+    for (label, player_input) in &player_inputs {
+        let mut problems = validate_input(player_input);
+        if player_inputs.len() > 1 {
+            problems = problems.into_iter().map(|problem| format!("{}: {}", label, problem)).collect();
+        }
+        if !problems.is_empty() {
+            if args.strict {
+                return Err(GenError::InvalidInput(problems));
+            }
+            for problem in &problems {
+                warn!("{}", problem);
+            }
+        }
+        if let Some(player_name) = &player_input.player_name {
+            validate_player_name(player_name)?;
+        }
+    }
+    if input.mist_fragments_required == 0 {
+        return Err(GenError::Config("mist_fragments_required must be at least 1".to_string()));
+    }
+    if input.extra_mist_fragment_percent > 100 {
+        warn!("extra_mist_fragment_percent is {}, more than doubling the {} required mist fragments",
+              input.extra_mist_fragment_percent, input.mist_fragments_required);
+    }
+
+    // Fixtures are already local, so only live data goes through the cache,
+    // and a snapshot has to see every request to record it
+    let use_cache = !args.no_cache && args.offline.is_none() && args.snapshot.is_none();
+    let cache_dir = resolve_path(&args.cache_dir);
+
+    // The guard saves the rate limit state however main returns
+    let snapshot_path = args.snapshot.as_deref().map(resolve_path);
+    let replaying = snapshot_path.as_ref().is_some_and(|path| path.exists());
+    let (data, _rate_state_guard): (Arc<dyn Gw2Source>, _) = if let (Some(path), true) = (&snapshot_path, replaying) {
+        info!("Replaying snapshot {}", path.display());
+        (Arc::new(SnapshotSource::replay(path.clone())?), None)
+    } else if let Some(offline_dir) = &args.offline {
+        (Arc::new(FixtureSource { dir: resolve_path(offline_dir) }), None)
+    } else {
+        let api_keys = find_api_keys(args, Some(&input));
+        if api_keys.is_empty() {
+            return Err(GenError::MissingApiKey(input_path));
+        }
+
+        let client = Arc::new(build_client(args)?);
+        let rate_state_guard = args.rate_state.as_ref().map(|rate_state| {
+            let path = resolve_path(rate_state);
+            client.restore_rate_state(&path);
+            RateStateGuard { client: Arc::clone(&client), path }
+        });
+        let mut sources: Vec<_> = api_keys.into_iter().map(|api_key| ApiSource {
+            client: Arc::clone(&client),
+            base_url: args.api_base_url.clone(),
+            api_key,
+            lang: args.lang,
+            completed_cache: use_cache.then(|| cache_dir.join("completed")),
+        }).collect();
+        let source: Arc<dyn Gw2Source> = if sources.len() == 1 {
+            Arc::new(sources.remove(0))
+        } else {
+            info!("Generating across {} accounts", sources.len());
+            Arc::new(MultiAccountSource::new(sources))
+        };
+        (source, rate_state_guard)
+    };
+    let recorder = match &snapshot_path {
+        Some(path) if !replaying => Some(Arc::new(SnapshotSource::record(path.clone(), Arc::clone(&data)))),
+        _ => None,
+    };
+    let data: Arc<dyn Gw2Source> = match &recorder {
+        Some(recorder) => recorder.clone(),
+        None => data,
     };
-    println!("{:?}", input);
 
-    let reqwest_client = Arc::new(RateLimitedReqwestClient::new());
+    stage.set("checking the API key");
+    if let Some(token_info) = data.token_info().await? {
+        let missing: Vec<_> = REQUIRED_PERMISSIONS.into_iter()
+            .filter(|permission| !token_info.permissions.contains(*permission))
+            .collect();
+        if !missing.is_empty() {
+            return Err(GenError::MissingPermissions(missing));
+        }
+    }
+
+    stage.set("listing the account's characters");
+    let account_characters = data.characters().await?;
+    // Key everything by the API's spelling so names typed in another case still match
+    for (_, player_input) in &mut player_inputs {
+        let mut input_names: Vec<String> = player_input.characters.keys().cloned().collect();
+        input_names.sort();
+        let mut canonical = HashMap::<String, CharacterInput>::new();
+        let mut spelled = HashMap::<String, String>::new();
+        for name in input_names {
+            let character = player_input.characters.remove(&name).expect("name was just listed");
+            let account_name = canonical_name(&account_characters, name.clone());
+            // Two spellings of one character would otherwise quietly keep whichever came last
+            if let Some(first) = spelled.insert(account_name.clone(), name.clone()) {
+                return Err(GenError::InvalidInput(vec![
+                    format!("{} and {} are both {} on the account", first, name, account_name)]));
+            }
+            canonical.insert(account_name, character);
+        }
+        player_input.characters = canonical;
+    }
+    let only: Vec<String> = args.only.iter()
+        .map(|name| canonical_name(&account_characters, name.clone()))
+        .collect();
+    if args.all_characters {
+        for (_, player_input) in &mut player_inputs {
+            for name in account_characters.iter().filter(|name| only.is_empty() || only.contains(name)) {
+                player_input.characters.entry(name.clone()).or_default();
+            }
+        }
+    }
 
-    let character_names = {
-        let uri = format!("https://api.guildwars2.com/v2/characters?access_token={}", input.api_key);
-        let response = reqwest_client.get(&uri).await.send().await.unwrap();
-        let mut characters = response.json::<HashSet<String>>().await.unwrap();
-        if input.characters.len() > 0 {
-            characters.drain().filter(|char| {input.characters.contains_key(char)}).collect()
+    // Only narrow the account's characters when every player lists the ones it wants
+    let character_names: HashSet<String> =
+        if player_inputs.iter().all(|(_, player_input)| !player_input.characters.is_empty()) {
+            let (listed, mut unlisted): (Vec<String>, Vec<String>) = account_characters.into_iter()
+                .partition(|char| player_inputs.iter().any(|(_, player_input)| player_input.characters.contains_key(char)));
+            // --only already says which characters are wanted
+            if !unlisted.is_empty() && only.is_empty() {
+                unlisted.sort();
+                info!("Leaving out {} account characters the input doesn't list: {}", unlisted.len(), unlisted.join(", "));
+            }
+            listed.into_iter().collect()
         }
         else {
-            characters
+            account_characters
+        };
+    let character_names: HashSet<String> = if only.is_empty() {
+        character_names
+    } else {
+        for name in &only {
+            let new = player_inputs.iter().any(|(_, player_input)| {
+                player_input.characters.get(name).is_some_and(|character| character.new)
+            });
+            if !character_names.contains(name) && !new {
+                warn!("--only {} doesn't match any character on the account", name);
+            }
         }
+        character_names.into_iter().filter(|name| only.contains(name)).collect()
     };
 
-    println!("{:?}", character_names);
+    info!("Generating for {} characters", character_names.len());
+    debug!("{:?}", character_names);
 
+    let show_progress = !args.quiet;
+
+    stage.set("fetching characters");
+    let mut skipped = Vec::new();
     let characters = {
         let mut tasks = FuturesUnordered::new();
         for name in &character_names {
-            let uri =
-                format!("https://api.guildwars2.com/v2/characters/{}/core?access_token={}",
-                        name,
-                        input.api_key);
-            tasks.push(tokio::spawn(reqwest_client.get(uri).await.send()));
+            let data = Arc::clone(&data);
+            let name = name.clone();
+            tasks.push(tokio::spawn(async move {
+                let character = data.character(&name).await;
+                (name, character)
+            }));
         }
 
+        let progress = progress_bar(show_progress, "characters", tasks.len() as u64);
         let mut characters = HashMap::new();
         while let Some(finished_task) = tasks.next().await {
-            let character: Character = finished_task.unwrap().unwrap().json().await.unwrap();
-            characters.insert(character.name.clone(), character);
+            let (name, character) = finished_task?;
+            progress.inc(1);
+            match character {
+                Ok(character) => {
+                    characters.insert(character.name.clone(), character);
+                }
+                Err(err) if args.on_error == OnError::Skip => {
+                    warn!("Skipping {}: {}", name, err);
+                    skipped.push(name);
+                }
+                Err(err) => return Err(err),
+            }
         }
+        progress.finish_and_clear();
 
         characters
     };
+    // Otherwise they'd be generated as if they weren't on the account
+    for (_, player_input) in &mut player_inputs {
+        for name in &skipped {
+            player_input.characters.remove(name);
+        }
+    }
 
-    let seasons = {
-        let mut tasks = FuturesUnordered::new();
-        for storyline in Storyline::iter() {
-            let uri = format!("https://api.guildwars2.com/v2/stories/seasons/{}",
-                              storyline.id());
-            println!("{}", uri);
-            tasks.push(tokio::spawn(reqwest_client.get(uri).await.send()));
+    let cache_ttl = Duration::from_secs(args.cache_ttl_hours * 60 * 60);
+    let lang = args.lang.code();
+    let seasons_cache = cache_dir.join(format!("seasons.{}.json", lang));
+    let quests_cache = cache_dir.join(format!("quests.{}.json", lang));
+
+    stage.set("fetching seasons and quests");
+    // Seasons and quests don't depend on each other, so both are fetched at once against the shared limiter
+    let bars = MultiProgress::new();
+    let seasons = async {
+        let cached_seasons = if !use_cache { None } else {
+            load_cache::<HashMap<String, Season>>(&seasons_cache, cache_ttl)
+                .filter(|seasons| Storyline::iter().all(|storyline| seasons.contains_key(storyline.id())))
+        };
+        if let Some(seasons) = cached_seasons {
+            info!("Using cached seasons from {}", seasons_cache.display());
+            return Ok(seasons);
         }
 
-        let mut seasons = HashMap::<String, Season>::new();
-        while let Some(finished_task) = tasks.next().await {
-            let season: Season = finished_task.unwrap().unwrap().json().await.unwrap();
-            seasons.insert(season.id.clone(), season);
+        info!("Fetching seasons");
+        let progress = bars.add(progress_bar(show_progress, "seasons", 0));
+        let (seasons, missing) = fetch_seasons(&data, progress).await?;
+        if !missing.is_empty() {
+            if args.strict {
+                return Err(GenError::MissingSeasons(missing_season_names(&missing)));
+            }
+            for storyline in &missing {
+                warn!("Season {} for {} is missing from the API, skipping the storyline",
+                      storyline.id(), storyline.snake_case());
+            }
         }
 
-        seasons
+        // An incomplete set would be fetched again next time anyway
+        if use_cache && missing.is_empty() {
+            store_cache(&seasons_cache, &seasons);
+        }
+        Ok(seasons)
     };
 
-    let quest_ids = {
-        let response = reqwest_client.get("https://api.guildwars2.com/v2/quests").await.send().await.unwrap();
-        response.json::<Vec<u32>>().await.unwrap()
-    };
+    let quests = async {
+        let cached_quests = if !use_cache { None } else {
+            load_cache::<HashMap<u32, Quest>>(&quests_cache, cache_ttl)
+        };
+        if let Some(quests) = cached_quests {
+            info!("Using cached quests from {}", quests_cache.display());
+            return Ok(Some(quests));
+        }
+        if args.limit_quests_detail {
+            // Fetched once the completed quests are known
+            return Ok(None);
+        }
 
-    let quests = {
-        let mut quests = HashMap::new();
+        info!("Fetching quests");
+        let progress = bars.add(progress_bar(show_progress, "quests", 0));
+        let quests: HashMap<u32, Quest> = data.quests(&progress).await?.into_iter()
+            .map(|q| (q.id, q))
+            .collect();
+        progress.finish_and_clear();
 
-        let mut tasks = FuturesUnordered::new();
-        for quest_chunk in quest_ids.as_slice().chunks(100) {
-            let uri = quest_chunk.iter().fold("https://api.guildwars2.com/v2/quests?ids=".to_string(),
-                                                 |str, id| format!("{}{},", str, id)
-            );
-            println!("{}", uri);
-            tasks.push(tokio::spawn(reqwest_client.get(uri).await.send()));
-            // categories.extend(reqwest_client.get(uri).await.send().await.unwrap().json::<Vec<AchievementCategory>>().await.unwrap());
+        if use_cache {
+            store_cache(&quests_cache, &quests);
         }
+        Ok::<_, GenError>(Some(quests))
+    };
 
-        while let Some(finished_task) = tasks.next().await {
-            let mut element_vec = finished_task.unwrap().unwrap().json::<Vec<Quest>>().await.unwrap();
-            let kv_iter = element_vec.drain(..).map(|q| (q.id, q));
-            quests.extend(kv_iter);
-        }
+    let (seasons, quests) = tokio::join!(seasons, quests);
+    let (seasons, quests) = (seasons?, quests?);
+    let future_seasons = if args.include_future_storylines {
+        info!("Looking for seasons newer than the known storylines");
+        stage.set("looking for new seasons");
+        discover_seasons(&data).await?
+    } else {
+        Vec::new()
+    };
+
+    stage.set("fetching completed quests");
+    let completed_quests: HashMap<String, HashSet<u32>> = {
+        let progress = progress_bar(show_progress, "completions", 0);
+        let fetched = fetch_all_completed(&data, characters.keys().cloned(), &progress).await?;
+        progress.finish_and_clear();
 
-        quests
+        fetched.into_iter().map(|(name, completed)| {
+            // Older characters and keys without progression have no quest data, so count them as fresh
+            let completed = completed.unwrap_or_else(|err| {
+                warn!("Couldn't fetch completed quests for {}, assuming none: {}", name, err);
+                HashSet::new()
+            });
+            (name, completed)
+        }).collect()
     };
 
+    let count_quests = quests.is_some();
+    let quests = match quests {
+        Some(quests) => quests,
+        None => {
+            let mut completed_ids: Vec<u32> = completed_quests.values().flatten().copied().collect();
+            completed_ids.sort_unstable();
+            completed_ids.dedup();
+            info!("Fetching {} completed quests", completed_ids.len());
+            let progress = progress_bar(show_progress, "quests", 0);
+            let quests = data.quests_by_id(&completed_ids, &progress).await?.into_iter()
+                .map(|q| (q.id, q))
+                .collect();
+            progress.finish_and_clear();
+            quests
+        }
+    };
 
-    let mut output = Output::default();
-    for (character_name, character_options) in input.characters {
-        let character = characters.get(&character_name);
+    let daily_monthly_ap = if args.scale_by_daily_monthly_ap {
+        stage.set("fetching the account");
+        match data.account().await? {
+            Some(account) => {
+                info!("Scaling achievement_weight by {} daily and monthly AP", account.daily_monthly_ap());
+                Some(account.daily_monthly_ap())
+            }
+            None => {
+                warn!("--scale-by-daily-monthly-ap needs an API key, leaving achievement_weight as is");
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-        let weight = character_options.weight;
-        output.game_options.character.insert(character_name.clone(), weight);
+    let stamp = if args.stamp {
+        stage.set("fetching the game build");
+        let build = match data.build_id().await? {
+            Some(build_id) => build_id.to_string(),
+            None => "unknown, not generated from the API".to_string(),
+        };
+        let input_name = match input_path.file_name() {
+            Some(name) if input_path != Path::new("-") => name.to_string_lossy().into_owned(),
+            _ => "stdin".to_string(),
+        };
+        Some(format!("# Generated by {} {}\n# At: {}\n# API build: {}\n# Input: {}\n",
+                     env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),
+                     httpdate::fmt_http_date(std::time::SystemTime::now()), build, input_name))
+    } else {
+        None
+    };
 
-        let mut trigger = Trigger::new("character".to_string(), character_name.clone());
-        trigger.options.insert("Guild Wars 2".to_string(), HashMap::new());
+    if let Some(recorder) = &recorder {
+        recorder.save()?;
+        info!("Recorded snapshot {}", recorder.path.display());
+    }
 
+    let options = BuildOptions {
+        strict: args.strict,
+        count_quests,
+        storyline_mode: args.storyline_mode,
+        min_remaining: args.min_remaining,
+        verbose_quests: args.verbose_quests,
+        auto_weight: args.auto_weight,
+        character_order: args.sort_characters,
+        future_seasons,
+    };
+    stage.set("generating");
+    let mut generated = Vec::new();
+    for (label, player_input) in &player_inputs {
+        let (mut output, mut summary) = build_output(player_input, &options,
+                                                     &characters, &seasons, &quests, &completed_quests)?;
+        for character in &mut summary.characters {
+            character.account = data.account_of(&character.name);
+        }
+        if let (Some(points), Some(table)) = (daily_monthly_ap, output.game_options.weight_table_mut("achievement_weight")) {
+            scale_fixed_value(table, points);
+        }
 
-        trigger.options.get_mut("Guild Wars 2").unwrap()
-            .insert("character_profession".to_string(), OptionValue::Table(HashMap::new()));
-        trigger.options.get_mut("Guild Wars 2").unwrap()
-            .insert("character_race".to_string(), OptionValue::Table(HashMap::new()));
+        if args.prune_zeros {
+            output.game_options.prune_zero_weights();
+        }
 
-        let completed_quest_ids;
-        let profession;
-        let race;
-        if let Some(character) = character {
-            profession = character.profession.clone();
-            race = character.race.clone();
+        let collisions = find_trigger_collisions(&output.game_options.triggers);
+        if !collisions.is_empty() {
+            for collision in &collisions {
+                warn!("Duplicate trigger {}", collision);
+            }
+            return Err(GenError::TriggerCollisions(collisions));
+        }
 
-            completed_quest_ids = Some({
-                let uri = format!("https://api.guildwars2.com/v2/characters/{}/quests?access_token={}", &character_name, input.api_key);
-                println!("{}", uri);
-                let response = reqwest_client.get(uri).await.send().await.unwrap();
-                response.json::<HashSet<u32>>().await.unwrap()
-            });
+        if args.validate_output {
+            let problems = validate_output(&output.game_options)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    warn!("{}", problem);
+                }
+                return Err(GenError::InvalidOutput(problems));
+            }
+        }
 
+        if !args.quiet {
+            if player_inputs.len() > 1 {
+                eprintln!("{}:", label);
+            }
+            eprint!("{}", summary);
         }
-        else {
-            profession = "random".to_string();
-            race = "random".to_string();
-            completed_quest_ids = None;
+        generated.push((label, output, summary));
+    }
+
+    if let Some(report) = &args.report {
+        // Completion doesn't depend on the player, so each character is reported once
+        let mut report_summary = Summary::default();
+        for (_, _, summary) in &generated {
+            for character in &summary.characters {
+                if !report_summary.characters.iter().any(|reported| reported.name == character.name) {
+                    report_summary.characters.push(character.clone());
+                }
+            }
         }
+        let report_path = resolve_path(report);
+        write_atomically(&report_path, |file| {
+            report_summary.write_csv(std::io::BufWriter::new(file)).map_err(|err| GenError::File(report_path.clone(), err))
+        })?;
+        info!("Wrote report {}", report_path.display());
+    }
 
-        trigger.options.get_mut("Guild Wars 2").unwrap()
-            .get_mut("character_profession").unwrap()
-            .insert(profession, default_weight());
-        trigger.options.get_mut("Guild Wars 2").unwrap()
-            .get_mut("character_race").unwrap()
-            .insert(race, default_weight());
+    if !skipped.is_empty() {
+        skipped.sort();
+        warn!("Skipped {} characters after errors: {}", skipped.len(), skipped.join(", "));
+    }
 
-        trigger.options.get_mut("Guild Wars 2").unwrap()
-            .insert("storyline".to_string(), OptionValue::Table(HashMap::new()));
+    if args.annotate && args.output_format == Format::Json {
+        warn!("--annotate only works with yaml output, writing json without comments");
+    }
+    if args.pretty.is_some() && args.output_format == Format::Json {
+        warn!("--pretty only works with yaml output, json is always written indented");
+    }
+    if args.stamp && args.output_format == Format::Json {
+        warn!("--stamp only works with yaml output, writing json without a header");
+    }
+    // Json has no comments to put it in
+    let stamp = stamp.as_deref().filter(|_| args.output_format == Format::Yaml);
 
-        let storyline_options = character_options.storyline;
-        let mut storyline_triggers = Vec::new();
-        for storyline in Storyline::iter() {
+    if let Some(split_dir) = &args.split_by_character {
+        let split_dir = resolve_path(split_dir);
+        for (label, output, _) in &generated {
+            if generated.len() == 1 {
+                write_split(&split_dir, output, args.pretty, stamp)?;
+            } else {
+                write_split(&split_dir.join(label), output, args.pretty, stamp)?;
+            }
+        }
+        return Ok(());
+    }
 
-            let weight = if let Some (options) = &storyline_options {
-                if options.contains_key(storyline.snake_case()) {
-                    options[storyline.snake_case()]
+    let output_path = match output_path {
+        Some(output_path) if !args.dry_run => output_path,
+        _ => {
+            let mut stdout = std::io::stdout().lock();
+            for (index, (_, output, summary)) in generated.iter().enumerate() {
+                // Archipelago reads several players from one yaml as separate documents
+                if index > 0 && args.output_format == Format::Yaml {
+                    writeln!(stdout, "---").map_err(|err| GenError::File(PathBuf::from("-"), err))?;
                 }
-                else {
-                    continue;
+                write_stamp(&mut stdout, stamp, Path::new("-"))?;
+                write_output(&mut stdout, output, args.output_format, args.annotate.then_some(summary), args.pretty)?;
+                if args.output_format == Format::Json {
+                    writeln!(stdout).map_err(|err| GenError::File(PathBuf::from("-"), err))?;
                 }
-            } else {
-                storyline.default_weight()
+            }
+            if args.dry_run {
+                info!("Dry run, output file was not written");
+            }
+            return Ok(());
+        }
+    };
+
+    for (label, output, summary) in &generated {
+        let annotations = args.annotate.then_some(summary);
+        let path = if generated.len() == 1 {
+            output_path.clone()
+        } else {
+            let extension = match args.output_format {
+                Format::Yaml => "yaml",
+                Format::Json => "json",
             };
+            output_path.with_file_name(format!("{}.{}", label, extension))
+        };
 
-            let season = &seasons[storyline.id()];
-            let completed_count =
-                if let Some(completed) = &completed_quest_ids {
-                     completed.iter().filter(|&q| season.story_ids.contains(&quests[q].story_id)).count()
-                }
-                else {
-                    0
-                }
-            ;
-            println!("{}", character_name);
-            println!("{:?}, count: {}", completed_quest_ids, completed_count);
-            println!("{}: {:?}", storyline.snake_case(), season);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| GenError::File(parent.to_path_buf(), err))?;
+        }
+        if args.merge && path.exists() {
+            let merged = merge_output(&path, output)?;
+            let mut backup_name = path.file_name().unwrap_or_default().to_os_string();
+            backup_name.push(".bak");
+            let backup = path.with_file_name(backup_name);
+            fs::copy(&path, &backup).map_err(|err| GenError::File(backup.clone(), err))?;
+            write_atomically(&path, |mut file| {
+                write_stamp(&mut file, stamp, &path)?;
+                write_output(file, &merged, args.output_format, annotations, args.pretty)
+            })?;
+            info!("Merged into {}, the original is in {}", path.display(), backup.display());
+            continue;
+        }
+        if args.backup && path.exists() {
+            let backup = backup_output(&path, args.keep_backups)?;
+            info!("Backed up {} to {}", path.display(), backup.display());
+        }
+        write_atomically(&path, |mut file| {
+            write_stamp(&mut file, stamp, &path)?;
+            write_output(file, output, args.output_format, annotations, args.pretty)
+        })?;
+        info!("Wrote {}", path.display());
+        if args.verify_roundtrip {
+            verify_roundtrip(&path, output, args.output_format)?;
+            debug!("{} reads back unchanged", path.display());
+        }
+    }
+
+    Ok(())
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Canned API data, missing entries answering 404 like the live API
+    #[derive(Default)]
+    struct MockSource {
+        characters: HashMap<String, Character>,
+        seasons: HashMap<String, Season>,
+        quests: HashMap<u32, Quest>,
+        completed: HashMap<String, HashSet<u32>>,
+    }
+
+    fn not_found(what: &str) -> GenError {
+        GenError::Fetch(FetchError::Status { status: StatusCode::NOT_FOUND, message: format!("no such {}", what) })
+    }
+
+    #[async_trait]
+    impl Gw2Source for MockSource {
+        async fn token_info(&self) -> Result<Option<TokenInfo>, GenError> {
+            Ok(None)
+        }
 
+        async fn characters(&self) -> Result<HashSet<String>, GenError> {
+            Ok(self.characters.keys().cloned().collect())
+        }
 
-            // for (id, quest) in quests.iter().filter(|(&id, q)| season.story_ids.contains(&q.story_id)) {
-            //     println!("{}: {}", quest.name, if completed_quest_ids.contains(&id) {"Complete"} else {"Incomplete"});
-            // }
+        async fn character(&self, name: &str) -> Result<Character, GenError> {
+            self.characters.get(name).cloned().ok_or_else(|| not_found("character"))
+        }
 
-            let intermediate_option_result = format!("{} {}", storyline.snake_case().to_string(), character_name.clone());
-            trigger.options.get_mut("Guild Wars 2").unwrap()
-                .get_mut("storyline").unwrap()
-                .insert(intermediate_option_result.clone(), weight);
+        async fn season(&self, id: &str) -> Result<Season, GenError> {
+            self.seasons.get(id).cloned().ok_or_else(|| not_found("season"))
+        }
 
-            let mut storyline_trigger = Trigger::new("storyline".to_string(), intermediate_option_result);
-            storyline_trigger.options.insert("Guild Wars 2".to_string(), HashMap::new());
-            storyline_trigger.options.get_mut("Guild Wars 2").unwrap()
-                .insert("max_quests".to_string(), OptionValue::Value(format!("{}", storyline.max_quests() - completed_count)));
+        async fn season_ids(&self) -> Result<Vec<String>, GenError> {
+            Ok(self.seasons.keys().cloned().collect())
+        }
 
+        async fn quests(&self, _progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+            Ok(self.quests.values().cloned().collect())
+        }
 
-            // storyline_trigger.options.get_mut("Guild Wars 2").unwrap()
-            //     .insert("max_training".to_string(), OptionValue::Value(format!("{}", storyline.max_training(profession == "Revenant") - completed_count)));
-            storyline_trigger.options.get_mut("Guild Wars 2").unwrap()
-                .insert("storyline".to_string(), OptionValue::Value(storyline.snake_case().to_string()));
+        async fn quests_by_id(&self, ids: &[u32], _progress: &ProgressBar) -> Result<Vec<Quest>, GenError> {
+            Ok(ids.iter().filter_map(|id| self.quests.get(id).cloned()).collect())
+        }
 
+        async fn completed_quests(&self, name: &str) -> Result<HashSet<u32>, GenError> {
+            self.completed.get(name).cloned().ok_or_else(|| not_found("character"))
+        }
 
-            storyline_triggers.push(storyline_trigger);
+        async fn account(&self) -> Result<Option<Account>, GenError> {
+            Ok(None)
         }
 
-        output.game_options.triggers.push(trigger);
-        output.game_options.triggers.extend(storyline_triggers)
+        async fn build_id(&self) -> Result<Option<u32>, GenError> {
+            Ok(None)
+        }
+    }
+
+    // Every storyline gets a season with one story of five quests, numbered from its position times 100
+    fn mock_source() -> MockSource {
+        let mut mock = MockSource::default();
+        for (index, storyline) in Storyline::iter().enumerate() {
+            let story_id = index as u32 + 1;
+            mock.seasons.insert(storyline.id().to_string(), Season {
+                id: storyline.id().to_string(),
+                name: storyline.snake_case().to_string(),
+                story_ids: HashSet::from([story_id]),
+            });
+            for quest in 0..5 {
+                let id = story_id * 100 + quest;
+                mock.quests.insert(id, Quest { id, name: format!("Quest {}", id), story_id });
+            }
+        }
+        mock.characters.insert("Alice".to_string(), Character {
+            name: "Alice".to_string(),
+            race: "Human".to_string(),
+            profession: "Guardian".to_string(),
+        });
+        mock.completed.insert("Alice".to_string(), HashSet::new());
+        mock
+    }
+
+    fn quest_ids(storyline: Storyline) -> HashSet<u32> {
+        let story_id = Storyline::iter().position(|other| other == storyline).unwrap() as u32 + 1;
+        (0..5).map(|quest| story_id * 100 + quest).collect()
+    }
+
+    fn build_options() -> BuildOptions {
+        BuildOptions {
+            strict: false,
+            count_quests: true,
+            storyline_mode: StorylineMode::Explicit,
+            min_remaining: 0,
+            verbose_quests: false,
+            auto_weight: AutoWeight::Off,
+            character_order: CharacterOrder::Alpha,
+            future_seasons: Vec::new(),
+        }
+    }
+
+    fn parse_input(yaml: &str) -> Input {
+        serde_yaml::from_str(yaml).expect("test input parses")
+    }
+
+    // Fetches everything through the source the way generate does, then builds the output from it
+    async fn build_from(data: Arc<dyn Gw2Source>, input: &Input, options: &BuildOptions) -> Output {
+        let mut characters = HashMap::new();
+        for name in data.characters().await.unwrap() {
+            characters.insert(name.clone(), data.character(&name).await.unwrap());
+        }
+        let (seasons, missing) = fetch_seasons(&data, ProgressBar::hidden()).await.unwrap();
+        assert!(missing.is_empty());
+        let quests = data.quests(&ProgressBar::hidden()).await.unwrap().into_iter()
+            .map(|quest| (quest.id, quest))
+            .collect();
+        let completed = fetch_all_completed(&data, characters.keys().cloned(), &ProgressBar::hidden()).await.unwrap()
+            .into_iter()
+            .map(|(name, completed)| (name, completed.unwrap()))
+            .collect();
+        build_output(input, options, &characters, &seasons, &quests, &completed).unwrap().0
+    }
+
+    fn trigger<'a>(output: &'a Output, option_name: &str, option_result: &str) -> Option<&'a Trigger> {
+        output.game_options.triggers.iter()
+            .find(|trigger| trigger.option_name == option_name && trigger.option_result == option_result)
+    }
+
+    fn game_option<'a>(trigger: &'a Trigger, name: &str) -> &'a OptionValue {
+        &trigger.options[GAME_NAME][name]
+    }
+
+    #[tokio::test]
+    async fn mock_source_generates_triggers() {
+        let mut source = mock_source();
+        source.completed.insert("Alice".to_string(), quest_ids(Storyline::Core).into_iter().take(2).collect());
+        let input = parse_input("characters:\n  Alice:\n    storyline:\n      core: 10\n      season_1: 20\n");
+
+        let output = build_from(Arc::new(source), &input, &build_options()).await;
+
+        let character = trigger(&output, "character", "Alice").unwrap();
+        assert_eq!(game_option(character, "storyline"), &OptionValue::Table(BTreeMap::from([
+            ("core Alice".to_string(), 10),
+            ("season_1 Alice".to_string(), 20),
+        ])));
+        assert_eq!(game_option(character, "character_profession"),
+                   &OptionValue::Table(BTreeMap::from([("Guardian".to_string(), 50)])));
+        let core = trigger(&output, "storyline", "core Alice").unwrap();
+        assert_eq!(game_option(core, "max_quests"), &OptionValue::Number(3));
+        assert_eq!(game_option(core, "storyline"), &OptionValue::Value("core".to_string()));
+        assert_eq!(output.game_options.triggers.len(), 3);
+    }
+
+    #[test]
+    fn storyline_ids_and_names_are_unique() {
+        let ids: HashSet<_> = Storyline::iter().map(|storyline| storyline.id()).collect();
+        let names: HashSet<_> = Storyline::iter().map(|storyline| storyline.snake_case()).collect();
+        assert_eq!(ids.len(), Storyline::iter().count());
+        assert_eq!(names.len(), Storyline::iter().count());
+    }
+
+    #[tokio::test]
+    async fn build_output_for_a_fixed_input() {
+        let input = parse_input("player_name: Tester\ndescription: Test run\ncharacters:\n  Alice:\n    weight: 30\n\
+                                 \x20   storyline:\n      core: 1\n  Bob:\n    new: true\n    storyline:\n      core: 2\n");
+
+        let output = build_from(Arc::new(mock_source()), &input, &build_options()).await;
+
+        assert_eq!(output.name, "Tester");
+        assert_eq!(output.description, "Test run");
+        assert_eq!(output.game_options.character,
+                   BTreeMap::from([("Alice".to_string(), 30), ("Bob".to_string(), 50)]));
+        let names: Vec<_> = output.game_options.triggers.iter()
+            .map(|trigger| format!("{}: {}", trigger.option_name, trigger.option_result))
+            .collect();
+        assert_eq!(names, ["character: Alice", "storyline: core Alice", "character: Bob", "storyline: core Bob"]);
+        let bob = trigger(&output, "character", "Bob").unwrap();
+        assert_eq!(game_option(bob, "character_race"), &OptionValue::Table(BTreeMap::from([("random".to_string(), 50)])));
+    }
+
+    #[tokio::test]
+    async fn more_completions_than_max_quests_leaves_the_storyline_out() {
+        // More core quests than the hardcoded count, all of them completed
+        let mut source = mock_source();
+        let core_story = 1;
+        let completed: HashSet<u32> = (0..Storyline::Core.max_quests() as u32 + 10).map(|quest| 9000 + quest).collect();
+        for id in &completed {
+            source.quests.insert(*id, Quest { id: *id, name: String::new(), story_id: core_story });
+        }
+        source.completed.insert("Alice".to_string(), completed);
+        let input = parse_input("characters:\n  Alice:\n    storyline:\n      core: 1\n");
+        let options = BuildOptions { count_quests: false, ..build_options() };
+
+        let output = build_from(Arc::new(source), &input, &options).await;
+
+        assert!(trigger(&output, "storyline", "core Alice").is_none());
+    }
+
+    #[tokio::test]
+    async fn a_completed_storyline_has_no_trigger() {
+        let mut source = mock_source();
+        source.completed.insert("Alice".to_string(), quest_ids(Storyline::Core));
+        let input = parse_input("characters:\n  Alice:\n    storyline:\n      core: 1\n      season_1: 1\n");
+
+        let output = build_from(Arc::new(source), &input, &build_options()).await;
+
+        assert!(trigger(&output, "storyline", "core Alice").is_none());
+        let character = trigger(&output, "character", "Alice").unwrap();
+        assert_eq!(game_option(character, "storyline"),
+                   &OptionValue::Table(BTreeMap::from([("season_1 Alice".to_string(), 1)])));
+    }
+
+    #[tokio::test]
+    async fn a_zero_weight_leaves_the_storyline_out() {
+        let input = parse_input("characters:\n  Alice:\n    storyline:\n      core: 0\n      season_1: 5\n");
+
+        let output = build_from(Arc::new(mock_source()), &input, &build_options()).await;
+
+        assert!(trigger(&output, "storyline", "core Alice").is_none());
+        let character = trigger(&output, "character", "Alice").unwrap();
+        assert_eq!(game_option(character, "storyline"),
+                   &OptionValue::Table(BTreeMap::from([("season_1 Alice".to_string(), 5)])));
+    }
+
+    #[tokio::test]
+    async fn per_character_include_competitive_keeps_the_global_default() {
+        let input = parse_input("characters:\n  Alice:\n    storyline:\n      core: 1\n    include_competitive:\n      \"false\": 50\n");
+
+        let output = build_from(Arc::new(mock_source()), &input, &build_options()).await;
+
+        let character = trigger(&output, "character", "Alice").unwrap();
+        assert_eq!(game_option(character, "include_competitive"),
+                   &OptionValue::Table(BTreeMap::from([("false".to_string(), 50)])));
+        assert_eq!(output.game_options.include_competitive.get("true"), Some(&10));
+    }
+
+    #[test]
+    fn batched_urls_join_ids_without_a_trailing_comma() {
+        assert_eq!(batched_id_urls("https://api/v2/quests?ids=", &[1, 22, 333]), ["https://api/v2/quests?ids=1,22,333"]);
+        assert!(batched_id_urls("https://api/v2/quests?ids=", &[]).is_empty());
+
+        let ids: Vec<u32> = (1..=MAX_IDS_PER_REQUEST as u32 + 1).collect();
+        let uris = batched_id_urls("q?ids=", &ids);
+        assert_eq!(uris.len(), 2);
+        assert_eq!(uris[1], format!("q?ids={}", MAX_IDS_PER_REQUEST + 1));
+
+        // Near the length limit before any ids, so a short batch has to be split
+        let base = format!("q?{}&ids=", "x".repeat(MAX_URL_LENGTH - 50));
+        let long_ids: Vec<u32> = (0..20).map(|id| 1_000_000_000 + id).collect();
+        let uris = batched_id_urls(&base, &long_ids);
+        assert!(uris.len() > 1);
+        assert!(uris.iter().all(|uri| uri.len() <= MAX_URL_LENGTH));
+    }
+
+    #[test]
+    fn max_quests_serializes_as_a_number() {
+        let trigger = storyline_trigger("core", "Alice", 42);
+        let yaml = serde_yaml::to_string(&trigger).unwrap();
+        assert!(yaml.contains("max_quests: 42\n"), "{}", yaml);
+        assert!(!yaml.contains("'42'"), "{}", yaml);
+    }
+
+    #[test]
+    fn option_values_round_trip_through_yaml() {
+        let value = OptionValue::List(vec![
+            OptionValue::Value("5".to_string()),
+            OptionValue::Number(5),
+            OptionValue::Table(BTreeMap::from([("early".to_string(), 50)])),
+            OptionValue::List(vec![OptionValue::Value("nested".to_string())]),
+        ]);
+        let yaml = serde_yaml::to_string(&value).unwrap();
+        assert_eq!(serde_yaml::from_str::<OptionValue>(&yaml).unwrap(), value);
+    }
+
+    #[test]
+    fn inserting_into_a_non_table_is_an_error() {
+        let mut value = OptionValue::Value("core".to_string());
+        assert!(matches!(value.insert("a".to_string(), 1), Err(OptionValueError::NotATable("value"))));
+        let mut list = OptionValue::List(Vec::new());
+        assert!(matches!(list.insert("a".to_string(), 1), Err(OptionValueError::NotATable("list"))));
+        let mut table = OptionValue::Table(BTreeMap::new());
+        assert_eq!(table.insert("a".to_string(), 1).unwrap(), None);
+        assert_eq!(table.insert("a".to_string(), 2).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn game_name_is_used_everywhere() {
+        let output = serde_yaml::to_value(Output::default()).unwrap();
+        assert_eq!(output["game"].as_str(), Some(GAME_NAME));
+        assert!(output.get(GAME_NAME).is_some_and(|options| options.is_mapping()));
+
+        let trigger = Trigger::new("character".to_string(), "Alice".to_string());
+        let trigger = serde_yaml::to_value(&trigger).unwrap();
+        assert_eq!(trigger["option_category"].as_str(), Some(GAME_NAME));
+        assert!(trigger["options"].get(GAME_NAME).is_some());
+    }
+
+    #[test]
+    fn trigger_helpers_fill_in_the_game_options() {
+        let mut trigger = Trigger::new("character".to_string(), "Alice".to_string());
+        trigger.with_game_option("max_quests", OptionValue::Number(3))
+            .with_game_option("storyline", OptionValue::Value("core".to_string()));
+        assert_eq!(game_option(&trigger, "max_quests"), &OptionValue::Number(3));
+        assert_eq!(game_option(&trigger, "storyline"), &OptionValue::Value("core".to_string()));
+
+        trigger.game_option_table_mut("character_race").insert("Human".to_string(), 50).unwrap();
+        trigger.game_option_table_mut("character_race").insert("Norn".to_string(), 10).unwrap();
+        assert_eq!(game_option(&trigger, "character_race"), &OptionValue::Table(BTreeMap::from([
+            ("Human".to_string(), 50),
+            ("Norn".to_string(), 10),
+        ])));
+        // Even with the game's options gone, the helpers put them back rather than panicking
+        trigger.options.clear();
+        trigger.with_game_option("storyline", OptionValue::Value("core".to_string()));
+        assert_eq!(trigger.options.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_written_gw2_yaml_reads_back_as_the_same_output() {
+        let input = parse_input("characters:\n  Alice:\n    storyline:\n      core: 1\n      season_1: 2\n");
+        let output = build_from(Arc::new(mock_source()), &input, &build_options()).await;
+
+        let mut yaml = Vec::new();
+        write_output(&mut yaml, &output, Format::Yaml, None, None).unwrap();
+        let reread: Output = serde_yaml::from_slice(&yaml).unwrap();
+        assert_eq!(reread, output);
     }
 
-    let file = File::create("gw2.yaml").unwrap();
-    serde_yaml::to_writer(file, &output).unwrap();
+    #[test]
+    fn misspelled_input_fields_are_rejected() {
+        let err = serde_yaml::from_str::<Input>("charcters:\n  Alice:\n").unwrap_err();
+        assert!(err.to_string().contains("unknown field `charcters`"), "{}", err);
+        let err = serde_yaml::from_str::<Input>("characters:\n  Alice:\n    weigth: 5\n").unwrap_err();
+        assert!(err.to_string().contains("unknown field `weigth`"), "{}", err);
+    }
+
+    type Handler = dyn Fn(&str, &HashMap<&str, &str>) -> (u16, &'static str, String) + Send + Sync;
+
+    // A stand-in for the API on a local port, each request answered with a status, extra headers and body
+    struct FakeApi {
+        base_url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl FakeApi {
+        fn start(handler: Arc<Handler>) -> Self {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let recorded = Arc::clone(&requests);
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let (handler, recorded) = (Arc::clone(&handler), Arc::clone(&recorded));
+                    std::thread::spawn(move || FakeApi::answer(stream.unwrap(), &*handler, &recorded));
+                }
+            });
+            FakeApi { base_url, requests }
+        }
+
+        fn answer(mut stream: std::net::TcpStream, handler: &Handler, recorded: &Mutex<Vec<String>>) {
+            use std::io::BufRead;
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            let target = request_line.split(' ').nth(1).unwrap_or_default().to_string();
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            let query: HashMap<&str, &str> = query.split('&').filter_map(|pair| pair.split_once('=')).collect();
+            recorded.lock().unwrap().push(path.to_string());
+
+            let (status, headers, body) = handler(path, &query);
+            write!(stream, "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                           Connection: close\r\n{}\r\n{}", status, body.len(), headers, body).unwrap();
+        }
+
+        fn hits(&self, path: &str) -> usize {
+            self.requests.lock().unwrap().iter().filter(|request| *request == path).count()
+        }
+
+        fn source(&self, api_key: &str) -> ApiSource {
+            let mut client = RateLimitedReqwestClient::with_quota(6000, Duration::ZERO).unwrap();
+            client.retry_base_delay = Duration::from_millis(1);
+            ApiSource {
+                client: Arc::new(client),
+                base_url: self.base_url.clone(),
+                api_key: api_key.to_string(),
+                lang: Lang::En,
+                completed_cache: None,
+            }
+        }
+    }
 
+    // Serves the mock's data in the API's shapes, for the key "good" only
+    fn serve(mock: MockSource) -> Arc<Handler> {
+        Arc::new(move |path: &str, query: &HashMap<&str, &str>| {
+            let json = |value: serde_json::Value| (200, "", value.to_string());
+            let missing = || (404, "", r#"{"text":"no such id"}"#.to_string());
+            if path != "/v2/quests" && !path.starts_with("/v2/stories/") && query.get("access_token") != Some(&"good") {
+                return (401, "", r#"{"text":"Invalid access token"}"#.to_string());
+            }
+            let path = path.strip_prefix("/v2/").unwrap_or(path);
+            let segments: Vec<&str> = path.split('/').collect();
+            match segments.as_slice() {
+                ["tokeninfo"] => json(serde_json::json!({"name": "test", "permissions": ["account", "characters", "progression"]})),
+                ["characters"] => json(serde_json::json!(mock.characters.keys().collect::<Vec<_>>())),
+                ["characters", name, "core"] => mock.characters.get(*name).map_or_else(missing, |character| {
+                    json(serde_json::to_value(character).unwrap())
+                }),
+                ["characters", name, "quests"] => mock.completed.get(*name).map_or_else(missing, |completed| {
+                    json(serde_json::json!(completed))
+                }),
+                ["stories", "seasons", id] => mock.seasons.get(*id).map_or_else(missing, |season| {
+                    json(serde_json::to_value(season).unwrap())
+                }),
+                ["quests"] => match query.get("ids") {
+                    Some(ids) => json(serde_json::to_value(ids.split(',')
+                        .filter_map(|id| mock.quests.get(&id.parse().unwrap()))
+                        .collect::<Vec<_>>()).unwrap()),
+                    None => json(serde_json::json!(mock.quests.keys().collect::<Vec<_>>())),
+                },
+                _ => missing(),
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn api_source_generates_from_the_fake_api() {
+        let mut mock = mock_source();
+        mock.completed.insert("Alice".to_string(), quest_ids(Storyline::Season1).into_iter().take(4).collect());
+        let api = FakeApi::start(serve(mock));
+        let input = parse_input("characters:\n  Alice:\n    storyline:\n      core: 10\n      season_1: 20\n");
+
+        let output = build_from(Arc::new(api.source("good")), &input, &build_options()).await;
+
+        let character = trigger(&output, "character", "Alice").unwrap();
+        assert_eq!(game_option(character, "character_race"), &OptionValue::Table(BTreeMap::from([("Human".to_string(), 50)])));
+        assert_eq!(game_option(trigger(&output, "storyline", "core Alice").unwrap(), "max_quests"), &OptionValue::Number(5));
+        assert_eq!(game_option(trigger(&output, "storyline", "season_1 Alice").unwrap(), "max_quests"),
+                   &OptionValue::Number(1));
+        assert_eq!(api.hits("/v2/characters/Alice/quests"), 1);
+        assert_eq!(api.hits(&format!("/v2/stories/seasons/{}", Storyline::Core.id())), 1);
+    }
+
+    #[tokio::test]
+    async fn throttled_requests_are_retried() {
+        let throttled = std::sync::atomic::AtomicBool::new(false);
+        let inner = serve(mock_source());
+        let api = FakeApi::start(Arc::new(move |path: &str, query: &HashMap<&str, &str>| {
+            if path == "/v2/characters" && !throttled.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return (429, "Retry-After: 0\r\n", r#"{"text":"too many requests"}"#.to_string());
+            }
+            inner(path, query)
+        }));
+
+        let characters = api.source("good").characters().await.unwrap();
+
+        assert_eq!(characters, HashSet::from(["Alice".to_string()]));
+        assert_eq!(api.hits("/v2/characters"), 2);
+    }
+
+    #[tokio::test]
+    async fn a_rejected_key_is_an_auth_error() {
+        let api = FakeApi::start(serve(mock_source()));
+
+        let err = api.source("bad").token_info().await.unwrap_err();
+
+        assert!(matches!(&err, GenError::InvalidApiKey(message) if message == "Invalid access token"), "{}", err);
+        assert_eq!(err.exit_code(), 3);
+        assert_eq!(api.hits("/v2/tokeninfo"), 1);
+    }
+
+    #[test]
+    fn daily_monthly_ap_scales_the_fixed_value_up_to_double() {
+        let table = || BTreeMap::from([("500".to_string(), 50), ("random".to_string(), 0)]);
+        for (points, expected) in [(0, "500"), (15000, "750"), (30000, "1000"), (90000, "1000")] {
+            let mut scaled = table();
+            scale_fixed_value(&mut scaled, points);
+            assert_eq!(scaled, BTreeMap::from([(expected.to_string(), 50), ("random".to_string(), 0)]), "{} AP", points);
+        }
+    }
 }